@@ -0,0 +1,511 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{
+    backend::{BackendError, Capture, SaveArtifact},
+    engine_builder::Browser,
+    extension::{self, cdp, Command},
+};
+use fancy_regex::Regex;
+use log::warn;
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+use snafu::ResultExt;
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use thirtyfour::{prelude::WebDriverResult, WebDriver, WebDriverCommands};
+use url::Url;
+
+pub const MANIFEST_FILE: &str = "manifest.jsonl";
+
+#[derive(Debug, Clone, Default)]
+pub struct ArtifactsConfig {
+    pub captures: Option<CaptureConfig>,
+    pub sources: Option<SourceConfig>,
+    /// Keep a page source in a result even if it's not saved.
+    pub keep_source: bool,
+}
+
+/// Settings of visual captures of visited pages.
+#[derive(Debug, Clone)]
+pub struct CaptureConfig {
+    pub dir: PathBuf,
+    pub pdf: bool,
+    /// If it's empty every page is captured.
+    pub filters: Vec<Regex>,
+}
+
+impl CaptureConfig {
+    pub fn is_captured(&self, url: &Url) -> bool {
+        self.filters.is_empty()
+            || self
+                .filters
+                .iter()
+                .any(|regex| regex.is_match(url.as_str()).unwrap_or(false))
+    }
+}
+
+/// Settings of storing page sources.
+///
+/// Sources are kept in a content addressed manner,
+/// a manifest file in the same directory maps urls to files.
+#[derive(Debug, Clone)]
+pub struct SourceConfig {
+    pub dir: PathBuf,
+    pub kind: SourceKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceKind {
+    /// A DOM after scripts on a page were run.
+    Dom,
+    /// An HTML as it was sent by a server.
+    Raw,
+}
+
+impl SourceKind {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Dom => "dom",
+            Self::Raw => "raw",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArtifactKind {
+    Screenshot,
+    Pdf,
+    Source,
+}
+
+impl ArtifactKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Screenshot => "screenshot",
+            Self::Pdf => "pdf",
+            Self::Source => "source",
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            Self::Screenshot => "png",
+            Self::Pdf => "pdf",
+            Self::Source => "html",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Artifact {
+    pub kind: ArtifactKind,
+    pub path: PathBuf,
+}
+
+/// Things captured on a page.
+#[derive(Debug, Default)]
+pub struct Captured {
+    /// Artifacts are `None` if none are configured.
+    pub artifacts: Option<Vec<Artifact>>,
+    pub source: Option<String>,
+    /// A body of the original response.
+    pub body: Option<String>,
+}
+
+/// Captures configured artifacts of a current page.
+/// It returns a page source as well if it was requested.
+/// A request id of the page is used to read a body of the original response
+/// in browsers which support CDP, which is a raw source and is kept in WARC files.
+///
+/// An artifact which failed to be captured is logged and left out,
+/// so the page is still collected.
+pub async fn capture(
+    driver: &WebDriver,
+    browser: &Browser,
+    cfg: &ArtifactsConfig,
+    url: &Url,
+    request_id: Option<&str>,
+) -> Captured {
+    let mut artifacts = Vec::new();
+    let mut keep = |result: Result<Artifact, BackendError>| match result {
+        Ok(artifact) => artifacts.push(artifact),
+        Err(err) => warn!("{}; the page is kept without it", err),
+    };
+
+    if let Some(captures) = cfg.captures.as_ref().filter(|c| c.is_captured(url)) {
+        let name = url_hash(url);
+
+        let png = full_page_screenshot(driver, browser).await;
+        keep(save_capture(
+            captures,
+            url,
+            &name,
+            ArtifactKind::Screenshot,
+            png,
+        ));
+
+        if captures.pdf {
+            let pdf = extension::execute(
+                driver,
+                Command::post("/print", json!({ "background": true })),
+            )
+            .await;
+            keep(save_capture(captures, url, &name, ArtifactKind::Pdf, pdf));
+        }
+    }
+
+    let kind = cfg
+        .sources
+        .as_ref()
+        .map(|sources| sources.kind)
+        .unwrap_or(SourceKind::Dom);
+    let is_body_used = cfg.keep_source || (cfg.sources.is_some() && kind == SourceKind::Raw);
+    let body = match (browser.cdp_vendor(), request_id) {
+        (Some(vendor), Some(request_id)) if is_body_used => {
+            match response_body(driver, vendor, request_id).await {
+                Ok(Some(body)) => Some(body),
+                Ok(None) => {
+                    warn!("A response body of {} isn't text", url);
+                    None
+                }
+                Err(err) => {
+                    warn!("Failed to obtain a response body of {} {}", url, err);
+                    None
+                }
+            }
+        }
+        _ => None,
+    };
+
+    let source = if cfg.sources.is_some() || cfg.keep_source {
+        match page_source(driver, kind, body.as_deref()).await {
+            Ok(html) => Some(html),
+            Err(err) => {
+                warn!("Unable to obtain a source of {}: {}", url, err);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    if let (Some(sources), Some(html)) = (cfg.sources.as_ref(), source.as_ref()) {
+        keep(save_source(sources, url, html).context(SaveArtifact {
+            address: url.clone(),
+        }));
+    }
+
+    let artifacts = if cfg.captures.is_some() || cfg.sources.is_some() {
+        Some(artifacts)
+    } else {
+        None
+    };
+
+    Captured {
+        artifacts,
+        source,
+        body,
+    }
+}
+
+fn save_capture(
+    captures: &CaptureConfig,
+    url: &Url,
+    name: &str,
+    kind: ArtifactKind,
+    content: WebDriverResult<Value>,
+) -> Result<Artifact, BackendError> {
+    let content = content.context(Capture {
+        address: url.clone(),
+    })?;
+    save(&captures.dir, name, kind, content).context(SaveArtifact {
+        address: url.clone(),
+    })
+}
+
+/// Makes an output record.
+/// If artifacts aren't configured the data is returned as it is,
+/// otherwise every page is a record with its artifacts, which might be none.
+pub fn record(url: &Url, data: Value, artifacts: Option<&[Artifact]>) -> Value {
+    let artifacts = match artifacts {
+        Some(artifacts) => artifacts,
+        None => return data,
+    };
+
+    let mut files = Map::new();
+    for artifact in artifacts {
+        files.insert(
+            artifact.kind.name().to_owned(),
+            json!(artifact.path.to_string_lossy()),
+        );
+    }
+
+    json!({
+        "url": url.as_str(),
+        "data": data,
+        "artifacts": files,
+    })
+}
+
+pub fn url_hash(url: &Url) -> String {
+    format!("{:x}", Sha256::digest(url.as_str().as_bytes()))
+}
+
+async fn full_page_screenshot(driver: &WebDriver, browser: &Browser) -> WebDriverResult<Value> {
+    if let Browser::Firefox = browser {
+        return extension::execute(driver, Command::get("/moz/screenshot/full")).await;
+    }
+
+    let vendor = match browser.cdp_vendor() {
+        Some(vendor) => vendor,
+        None => {
+            // there's no standard way to capture a whole page
+            let png = driver.screenshot_as_png().await?;
+            return Ok(Value::String(base64::encode(png)));
+        }
+    };
+
+    let metrics = cdp(driver, vendor, "Page.getLayoutMetrics", json!({})).await?;
+    let size = metrics
+        .get("cssContentSize")
+        .or_else(|| metrics.get("contentSize"))
+        .cloned()
+        .unwrap_or(Value::Null);
+    let width = size["width"].as_f64().unwrap_or(0.0);
+    let height = size["height"].as_f64().unwrap_or(0.0);
+
+    let screenshot = cdp(
+        driver,
+        vendor,
+        "Page.captureScreenshot",
+        json!({
+            "format": "png",
+            "captureBeyondViewport": true,
+            "clip": { "x": 0, "y": 0, "width": width, "height": height, "scale": 1 },
+        }),
+    )
+    .await?;
+
+    Ok(screenshot["data"].clone())
+}
+
+/// Obtains a page source.
+/// A raw page is a body of the original response if a browser keeps it,
+/// otherwise the page is requested one more time.
+async fn page_source(
+    driver: &WebDriver,
+    kind: SourceKind,
+    body: Option<&str>,
+) -> WebDriverResult<String> {
+    if kind == SourceKind::Raw {
+        if let Some(body) = body {
+            return Ok(body.to_owned());
+        }
+
+        // the page is requested one more time from the page itself
+        // so cookies and a session are the same as they were
+        let ret = driver
+            .execute_async_script(
+                r#"
+                const done = arguments[arguments.length - 1];
+                fetch(window.location.href, { credentials: "include" })
+                    .then((r) => r.text())
+                    .then(done, () => done(null));
+                "#,
+            )
+            .await?;
+
+        match ret.value().as_str() {
+            Some(html) => return Ok(html.to_owned()),
+            None => warn!("Failed to obtain a raw page; a DOM is used instead"),
+        }
+    }
+
+    driver.page_source().await
+}
+
+async fn response_body(
+    driver: &WebDriver,
+    vendor: &str,
+    request_id: &str,
+) -> WebDriverResult<Option<String>> {
+    let ret = cdp(
+        driver,
+        vendor,
+        "Network.getResponseBody",
+        json!({ "requestId": request_id }),
+    )
+    .await?;
+
+    let body = ret["body"].as_str().unwrap_or_default();
+    if ret["base64Encoded"].as_bool() != Some(true) {
+        return Ok(Some(body.to_owned()));
+    }
+
+    Ok(base64::decode(body)
+        .ok()
+        .and_then(|body| String::from_utf8(body).ok()))
+}
+
+/// Saves a page source if there's no such content yet
+/// and makes a record about it in a manifest.
+fn save_source(cfg: &SourceConfig, url: &Url, html: &str) -> io::Result<Artifact> {
+    let name = format!(
+        "{:x}.{}",
+        Sha256::digest(html.as_bytes()),
+        ArtifactKind::Source.extension()
+    );
+    let path = cfg.dir.join(&name);
+    if !path.exists() {
+        fs::write(&path, html)?;
+    }
+
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let mut line = json!({
+        "url": url.as_str(),
+        "file": name,
+        "kind": cfg.kind.name(),
+        "time": time,
+    })
+    .to_string();
+    line.push('\n');
+
+    // a line is written at once so records of different engines don't interleave
+    let mut manifest = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(cfg.dir.join(MANIFEST_FILE))?;
+    manifest.write_all(line.as_bytes())?;
+
+    Ok(Artifact {
+        kind: ArtifactKind::Source,
+        path,
+    })
+}
+
+fn save(dir: &Path, name: &str, kind: ArtifactKind, content: Value) -> io::Result<Artifact> {
+    let content = content
+        .as_str()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Expected a base64 string"))?;
+    let content =
+        base64::decode(content).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    let path = dir.join(format!("{}.{}", name, kind.extension()));
+    fs::write(&path, content)?;
+
+    Ok(Artifact { kind, path })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_without_artifacts() {
+        let url = Url::parse("https://example.net").unwrap();
+        assert_eq!(record(&url, json!("d1"), None), json!("d1"));
+        assert_eq!(
+            record(&url, json!("d1"), Some(&[])),
+            json!({ "url": "https://example.net/", "data": "d1", "artifacts": {} })
+        );
+    }
+
+    #[test]
+    fn record_with_artifacts() {
+        let url = Url::parse("https://example.net").unwrap();
+        let artifacts = [
+            Artifact {
+                kind: ArtifactKind::Screenshot,
+                path: PathBuf::from("captures/1.png"),
+            },
+            Artifact {
+                kind: ArtifactKind::Pdf,
+                path: PathBuf::from("captures/1.pdf"),
+            },
+        ];
+
+        assert_eq!(
+            record(&url, json!({ "title": "t" }), Some(&artifacts)),
+            json!({
+                "url": "https://example.net/",
+                "data": { "title": "t" },
+                "artifacts": { "screenshot": "captures/1.png", "pdf": "captures/1.pdf" },
+            })
+        );
+    }
+
+    #[test]
+    fn capture_filters() {
+        let mut cfg = CaptureConfig {
+            dir: PathBuf::new(),
+            pdf: false,
+            filters: Vec::new(),
+        };
+        assert!(cfg.is_captured(&Url::parse("https://example.net/a").unwrap()));
+
+        cfg.filters.push(Regex::new("/products/").unwrap());
+        assert!(!cfg.is_captured(&Url::parse("https://example.net/a").unwrap()));
+        assert!(cfg.is_captured(&Url::parse("https://example.net/products/1").unwrap()));
+    }
+
+    #[test]
+    fn save_source_is_content_addressed() {
+        let tmp = crate::test_dir();
+        let dir = tmp.path();
+        let cfg = SourceConfig {
+            dir: dir.to_owned(),
+            kind: SourceKind::Dom,
+        };
+
+        let a1 = save_source(
+            &cfg,
+            &Url::parse("https://example.net/1").unwrap(),
+            "<p>1</p>",
+        );
+        let a2 = save_source(
+            &cfg,
+            &Url::parse("https://example.net/2").unwrap(),
+            "<p>1</p>",
+        );
+        let a3 = save_source(
+            &cfg,
+            &Url::parse("https://example.net/3").unwrap(),
+            "<p>3</p>",
+        );
+
+        let (a1, a2, a3) = (a1.unwrap(), a2.unwrap(), a3.unwrap());
+        assert_eq!(a1.path, a2.path);
+        assert_ne!(a1.path, a3.path);
+        assert_eq!(fs::read_to_string(&a3.path).unwrap(), "<p>3</p>");
+
+        let manifest = fs::read_to_string(dir.join(MANIFEST_FILE)).unwrap();
+        let records = manifest
+            .lines()
+            .map(|l| serde_json::from_str::<Value>(l).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[1]["url"], json!("https://example.net/2"));
+        assert_eq!(records[1]["kind"], json!("dom"));
+        assert_eq!(dir.join(records[1]["file"].as_str().unwrap()), a1.path);
+    }
+
+    #[test]
+    fn url_hash_is_stable() {
+        let url = Url::parse("https://example.net").unwrap();
+        assert_eq!(url_hash(&url), url_hash(&url.clone()));
+        assert_eq!(url_hash(&url).len(), 64);
+        assert_ne!(
+            url_hash(&url),
+            url_hash(&Url::parse("https://example.net/a").unwrap())
+        );
+    }
+}
@@ -2,9 +2,19 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use crate::{
+    artifacts::{self, Artifact, ArtifactsConfig, Captured},
+    engine_builder::Browser,
+    extension::{self, Command},
+};
 use async_trait::async_trait;
-use serde_json::Value;
+use log::{debug, warn};
+use serde_json::{json, Value};
 use snafu::{ResultExt, Snafu};
+use std::{
+    io,
+    time::{Duration, Instant},
+};
 use thirtyfour::{error::WebDriverError, prelude::*};
 use url::Url;
 
@@ -18,22 +28,97 @@ pub trait Backend {
 pub struct SearchResult {
     pub urls: Vec<String>,
     pub data: Value,
+    /// Artifacts of a page if a backend captures them.
+    pub artifacts: Option<Vec<Artifact>>,
+    pub source: Option<String>,
+    /// A body of the original response if a backend obtained it.
+    pub body: Option<String>,
+    /// A status of a main document if a backend managed to obtain it.
+    pub status: Option<u16>,
+    pub headers: Vec<(String, String)>,
+    /// A time a page took to load.
+    pub load_time: Option<Duration>,
+    /// A time a check script took to run.
+    pub script_time: Option<Duration>,
 }
 
 impl SearchResult {
     pub fn new(urls: Vec<String>, data: Value) -> Self {
-        Self { urls, data }
+        Self {
+            urls,
+            data,
+            artifacts: None,
+            source: None,
+            body: None,
+            status: None,
+            headers: Vec::new(),
+            load_time: None,
+            script_time: None,
+        }
+    }
+}
+
+/// A class of an error which determines how it's handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ErrorClass {
+    Dns,
+    ConnectionRefused,
+    Tls,
+    ClientError,
+    ServerError,
+    TooManyRequests,
+    Timeout,
+    Script,
+    SessionLost,
+    HubUnavailable,
+    NavigationAborted,
+    Other,
+}
+
+impl ErrorClass {
+    pub const ALL: [ErrorClass; 12] = [
+        Self::Dns,
+        Self::ConnectionRefused,
+        Self::Tls,
+        Self::ClientError,
+        Self::ServerError,
+        Self::TooManyRequests,
+        Self::Timeout,
+        Self::Script,
+        Self::SessionLost,
+        Self::HubUnavailable,
+        Self::NavigationAborted,
+        Self::Other,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Dns => "dns",
+            Self::ConnectionRefused => "connection-refused",
+            Self::Tls => "tls",
+            Self::ClientError => "http-4xx",
+            Self::ServerError => "http-5xx",
+            Self::TooManyRequests => "http-429",
+            Self::Timeout => "timeout",
+            Self::Script => "script",
+            Self::SessionLost => "session-lost",
+            Self::HubUnavailable => "hub-unavailable",
+            Self::NavigationAborted => "navigation-aborted",
+            Self::Other => "other",
+        }
     }
 }
 
 #[derive(Debug, Snafu)]
 pub enum BackendError {
     #[snafu(display("Unable to open an address {}: {}", address, source))]
+    #[snafu(visibility(pub(crate)))]
     OpenAddress {
         source: WebDriverError,
         address: Url,
     },
     #[snafu(display("An error in running a script against {}: {}", address.as_str(), source))]
+    #[snafu(visibility(pub(crate)))]
     RunningScript {
         source: WebDriverError,
         address: Url,
@@ -43,6 +128,25 @@ pub enum BackendError {
         source: WebDriverError,
         address: Url,
     },
+    #[snafu(display("Unable to capture {}: {}", address, source))]
+    #[snafu(visibility(pub(crate)))]
+    Capture {
+        source: WebDriverError,
+        address: Url,
+    },
+    #[snafu(display("Unable to save an artifact of {}: {}", address, source))]
+    #[snafu(visibility(pub(crate)))]
+    SaveArtifact { source: io::Error, address: Url },
+    #[snafu(display("A page {} is not in an archive", address))]
+    #[snafu(visibility(pub(crate)))]
+    NotArchived { address: Url },
+    #[snafu(display("A page {} responded with a status {}", address, status))]
+    HttpStatus {
+        address: Url,
+        status: u16,
+        /// A delay requested by a `Retry-After` header.
+        retry_after: Option<Duration>,
+    },
     #[snafu(display("{}", msg))]
     Other { msg: String },
 }
@@ -53,7 +157,11 @@ impl BackendError {
             Self::RunningScript { source, .. } => Some(source),
             Self::OpenAddress { source, .. } => Some(source),
             Self::CollectLinks { source, .. } => Some(source),
-            Self::Other { .. } => None,
+            Self::Capture { source, .. } => Some(source),
+            Self::SaveArtifact { .. }
+            | Self::NotArchived { .. }
+            | Self::HttpStatus { .. }
+            | Self::Other { .. } => None,
         }
     }
 
@@ -64,52 +172,230 @@ impl BackendError {
         )
     }
 
+    pub fn class(&self) -> ErrorClass {
+        if let Self::HttpStatus { status, .. } = self {
+            return match status {
+                429 => ErrorClass::TooManyRequests,
+                400..=499 => ErrorClass::ClientError,
+                500..=599 => ErrorClass::ServerError,
+                _ => ErrorClass::Other,
+            };
+        }
+
+        if self.is_timeout() {
+            return ErrorClass::Timeout;
+        }
+
+        let err = match self.wb_error() {
+            Some(err) => err,
+            None => return ErrorClass::Other,
+        };
+
+        // browsers report network errors only by messages
+        let msg = err.to_string().to_lowercase();
+        let contains = |patterns: &[&str]| patterns.iter().any(|p| msg.contains(p));
+
+        if contains(&[
+            "error sending request",
+            "tcp connect error",
+            "session not created",
+        ]) {
+            ErrorClass::HubUnavailable
+        } else if matches!(err, WebDriverError::InvalidSessionId(..))
+            || contains(&["invalid session id", "no such window", "session deleted"])
+        {
+            ErrorClass::SessionLost
+        } else if contains(&["err_name_not_resolved", "dnsnotfound"]) {
+            ErrorClass::Dns
+        } else if contains(&["err_connection_refused", "connectionfailure"]) {
+            ErrorClass::ConnectionRefused
+        } else if contains(&["err_cert", "err_ssl", "nssfailure", "certerror"]) {
+            ErrorClass::Tls
+        } else if contains(&["err_aborted", "navigation aborted", "netreset"]) {
+            ErrorClass::NavigationAborted
+        } else if let Self::RunningScript { .. } = self {
+            ErrorClass::Script
+        } else {
+            ErrorClass::Other
+        }
+    }
+
+    /// Returns a delay after which a server asked to retry a request.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::HttpStatus { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+
     pub fn address(&self) -> Option<&Url> {
         match &self {
             Self::RunningScript { address, .. } => Some(address),
             Self::OpenAddress { address, .. } => Some(address),
             Self::CollectLinks { address, .. } => Some(address),
+            Self::Capture { address, .. } => Some(address),
+            Self::SaveArtifact { address, .. } => Some(address),
+            Self::NotArchived { address } => Some(address),
+            Self::HttpStatus { address, .. } => Some(address),
             Self::Other { .. } => None,
         }
     }
 }
 
+pub(crate) async fn collect_links(
+    driver: &WebDriver,
+    url: &Url,
+) -> Result<Vec<String>, BackendError> {
+    let links = driver
+        .find_elements(By::Tag("a"))
+        .await
+        .context(CollectLinks {
+            address: url.clone(),
+        })?;
+
+    let mut urls = Vec::new();
+    for link in links {
+        let href = link.get_attribute("href").await;
+        match href {
+            Ok(Some(href)) => {
+                urls.push(href);
+            }
+            Ok(None) | Err(thirtyfour::error::WebDriverError::StaleElementReference(..)) => {
+                continue
+            }
+            Err(err) => Err(err).context(CollectLinks {
+                address: url.clone(),
+            })?,
+        }
+    }
+
+    Ok(urls)
+}
+
+/// A response of a main document of a current page.
+#[derive(Debug, Default)]
+pub(crate) struct ResponseInfo {
+    pub status: Option<u16>,
+    pub headers: Vec<(String, String)>,
+    /// An id of the request in a browser by which its body might be read.
+    pub request_id: Option<String>,
+}
+
+/// Obtains a status and headers of a main document of a current page.
+///
+/// A status is taken from Navigation Timing API.
+/// Chrome also provides headers and a request id via performance logs.
+/// Failures are ignored as this information isn't crucial.
+pub(crate) async fn response_info(driver: &WebDriver, browser: &Browser) -> ResponseInfo {
+    if let Browser::Chrome = browser {
+        match chrome_document_response(driver).await {
+            Ok(Some(info)) => return info,
+            Ok(None) => (),
+            Err(err) => debug!("Failed to read performance logs {}", err),
+        }
+    }
+
+    let status = driver
+        .execute_script(
+            r#"
+            const entry = performance.getEntriesByType("navigation")[0];
+            return entry && entry.responseStatus ? entry.responseStatus : null;
+            "#,
+        )
+        .await
+        .map(|ret| ret.value().as_u64().map(|status| status as u16));
+
+    match status {
+        Ok(status) => ResponseInfo {
+            status,
+            ..ResponseInfo::default()
+        },
+        Err(err) => {
+            debug!("Failed to obtain a status of a page {}", err);
+            ResponseInfo::default()
+        }
+    }
+}
+
+async fn chrome_document_response(driver: &WebDriver) -> WebDriverResult<Option<ResponseInfo>> {
+    // reading logs clears them so only events of the last page are present
+    let logs = extension::execute(
+        driver,
+        Command::post("/se/log", json!({ "type": "performance" })),
+    )
+    .await?;
+    let current_url = driver.current_url().await?;
+
+    let mut document = None;
+    for entry in logs.as_array().into_iter().flatten() {
+        let message = match entry["message"]
+            .as_str()
+            .and_then(|m| serde_json::from_str::<Value>(m).ok())
+        {
+            Some(message) => message,
+            None => continue,
+        };
+
+        let message = &message["message"];
+        if message["method"] != "Network.responseReceived"
+            || message["params"]["type"] != "Document"
+        {
+            continue;
+        }
+
+        let response = message["params"]["response"].clone();
+        let is_current = response["url"] == current_url.as_str();
+        let request_id = message["params"]["requestId"].as_str().map(str::to_owned);
+        document = Some((response, request_id));
+
+        if is_current {
+            break;
+        }
+    }
+
+    let (response, request_id) = match document {
+        Some(document) => document,
+        None => return Ok(None),
+    };
+
+    let status = response["status"].as_u64().map(|status| status as u16);
+    let headers = response["headers"]
+        .as_object()
+        .into_iter()
+        .flatten()
+        .map(|(name, value)| {
+            let value = value.as_str().map(|v| v.to_owned()).unwrap_or_default();
+            (name.clone(), value)
+        })
+        .collect();
+
+    Ok(Some(ResponseInfo {
+        status,
+        headers,
+        request_id,
+    }))
+}
+
 pub struct WebDriverSearcher {
     driver: WebDriver,
     code: String,
+    browser: Browser,
+    artifacts: ArtifactsConfig,
 }
 
 #[async_trait]
 impl Backend for WebDriverSearcher {
     async fn search(&mut self, url: &Url) -> Result<SearchResult, BackendError> {
+        let started = Instant::now();
         self.driver.get(url.as_str()).await.context(OpenAddress {
             address: url.clone(),
         })?;
+        let load_time = started.elapsed();
 
-        let links = self
-            .driver
-            .find_elements(By::Tag("a"))
-            .await
-            .context(CollectLinks {
-                address: url.clone(),
-            })?;
-
-        let mut urls = Vec::new();
-        for link in links {
-            let href = link.get_attribute("href").await;
-            match href {
-                Ok(Some(href)) => {
-                    urls.push(href);
-                }
-                Ok(None) | Err(thirtyfour::error::WebDriverError::StaleElementReference(..)) => {
-                    continue
-                }
-                Err(err) => Err(err).context(CollectLinks {
-                    address: url.clone(),
-                })?,
-            }
-        }
+        let response = response_info(&self.driver, &self.browser).await;
+        let urls = collect_links(&self.driver, url).await?;
 
+        let started = Instant::now();
         let data = self
             .driver
             .execute_script(&self.code)
@@ -119,57 +405,77 @@ impl Backend for WebDriverSearcher {
             })?
             .value()
             .clone();
+        let script_time = started.elapsed();
+
+        let Captured {
+            artifacts,
+            source,
+            body,
+        } = artifacts::capture(
+            &self.driver,
+            &self.browser,
+            &self.artifacts,
+            url,
+            response.request_id.as_deref(),
+        )
+        .await;
 
-        Ok(SearchResult { urls, data })
+        Ok(SearchResult {
+            urls,
+            data,
+            artifacts,
+            source,
+            body,
+            status: response.status,
+            headers: response.headers,
+            load_time: Some(load_time),
+            script_time: Some(script_time),
+        })
     }
 
     async fn close(self) {
-        self.driver.quit().await.unwrap()
+        if let Err(err) = self.driver.quit().await {
+            warn!("Failed to quit a session {}", err);
+        }
     }
 }
 
 impl WebDriverSearcher {
-    pub fn new(driver: WebDriver, code: String) -> Self {
-        Self { driver, code }
+    pub fn new(
+        driver: WebDriver,
+        code: String,
+        browser: Browser,
+        artifacts: ArtifactsConfig,
+    ) -> Self {
+        Self {
+            driver,
+            code,
+            browser,
+            artifacts,
+        }
     }
 }
 
 pub struct SideRunner {
     driver: WebDriver,
     file: siderunner::File,
+    browser: Browser,
+    artifacts: ArtifactsConfig,
 }
 
 #[async_trait]
 impl Backend for SideRunner {
     async fn search(&mut self, url: &Url) -> Result<SearchResult, BackendError> {
+        let started = Instant::now();
         self.driver.get(url.as_str()).await.context(OpenAddress {
             address: url.clone(),
         })?;
+        let load_time = started.elapsed();
 
-        let links = self
-            .driver
-            .find_elements(By::Tag("a"))
-            .await
-            .context(CollectLinks {
-                address: url.clone(),
-            })?;
-
-        let mut urls = Vec::new();
-        for link in links {
-            let href = link.get_attribute("href").await;
-            match href {
-                Ok(Some(href)) => {
-                    urls.push(href);
-                }
-                Ok(None) | Err(thirtyfour::error::WebDriverError::StaleElementReference(..)) => {
-                    continue
-                }
-                Err(err) => Err(err).context(CollectLinks {
-                    address: url.clone(),
-                })?,
-            }
-        }
+        let response = response_info(&self.driver, &self.browser).await;
+        let urls = collect_links(&self.driver, url).await?;
 
+        let started = Instant::now();
         let mut runner = siderunner::Runner::new(&self.driver);
 
         runner
@@ -180,17 +486,76 @@ impl Backend for SideRunner {
             })?;
 
         let data = runner.get_value("RESULT").cloned().unwrap_or(Value::Null);
+        let script_time = started.elapsed();
+
+        let Captured {
+            artifacts,
+            source,
+            body,
+        } = artifacts::capture(
+            &self.driver,
+            &self.browser,
+            &self.artifacts,
+            url,
+            response.request_id.as_deref(),
+        )
+        .await;
 
-        Ok(SearchResult { urls, data })
+        Ok(SearchResult {
+            urls,
+            data,
+            artifacts,
+            source,
+            body,
+            status: response.status,
+            headers: response.headers,
+            load_time: Some(load_time),
+            script_time: Some(script_time),
+        })
     }
 
     async fn close(self) {
-        self.driver.close().await.unwrap()
+        if let Err(err) = self.driver.quit().await {
+            warn!("Failed to quit a session {}", err);
+        }
     }
 }
 
 impl SideRunner {
-    pub fn new(driver: WebDriver, file: siderunner::File) -> Self {
-        Self { driver, file }
+    pub fn new(
+        driver: WebDriver,
+        file: siderunner::File,
+        browser: Browser,
+        artifacts: ArtifactsConfig,
+    ) -> Self {
+        Self {
+            driver,
+            file,
+            browser,
+            artifacts,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn http_status_class() {
+        let err = |status| BackendError::HttpStatus {
+            address: Url::parse("https://example.net").unwrap(),
+            status,
+            retry_after: None,
+        };
+
+        assert_eq!(err(404).class(), ErrorClass::ClientError);
+        assert_eq!(err(429).class(), ErrorClass::TooManyRequests);
+        assert_eq!(err(503).class(), ErrorClass::ServerError);
+        assert_eq!(err(304).class(), ErrorClass::Other);
+        assert_eq!(
+            BackendError::Other { msg: String::new() }.class(),
+            ErrorClass::Other
+        );
     }
 }
@@ -0,0 +1,232 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! An HTTP endpoint which controls a running crawl.
+//!
+//! There's no authentication so it's supposed to be bound to a local address.
+//! Requests with an `Origin` header are rejected,
+//! so pages opened by a browser on the same machine can't send commands to it.
+//! Routes are:
+//!
+//! - `GET /statistics` returns statistics as JSON
+//! - `POST /pause` stops dispatching urls, pages which are being worked on are finished
+//! - `POST /resume`
+//! - `POST /seeds` adds urls from a body, one per line
+//! - `POST /limit` changes a limit of collected data to a number in a body, an empty body removes it
+//! - `POST /stop` finishes pages which are being worked on and stops a crawl
+
+use crate::workload::{Command, Statistics};
+use hyper::{
+    header::{CONTENT_TYPE, ORIGIN},
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use log::{error, warn};
+use std::{convert::Infallible, io, net::SocketAddr, sync::Arc};
+use tokio::sync::{mpsc, watch, Notify};
+use url::Url;
+
+/// Starts serving the endpoint in a background task until a shutdown is notified.
+/// It returns an address the server is bound to.
+pub fn serve(
+    addr: SocketAddr,
+    commands: mpsc::UnboundedSender<Command>,
+    stats: watch::Receiver<Statistics>,
+    shutdown: Arc<Notify>,
+) -> io::Result<SocketAddr> {
+    if !addr.ip().is_loopback() {
+        warn!(
+            "A control endpoint {} is reachable from other hosts, it has no authentication",
+            addr
+        );
+    }
+
+    let make_service = make_service_fn(move |_| {
+        let commands = commands.clone();
+        let stats = stats.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let commands = commands.clone();
+                let stats = stats.clone();
+                async move { Ok::<_, Infallible>(handle(req, &commands, &stats).await) }
+            }))
+        }
+    });
+
+    let server = Server::try_bind(&addr)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+        .serve(make_service);
+    let addr = server.local_addr();
+    let server = server.with_graceful_shutdown(async move { shutdown.notified().await });
+
+    tokio::spawn(async move {
+        if let Err(err) = server.await {
+            error!("Control server failed {}", err);
+        }
+    });
+
+    Ok(addr)
+}
+
+async fn handle(
+    req: Request<Body>,
+    commands: &mpsc::UnboundedSender<Command>,
+    stats: &watch::Receiver<Statistics>,
+) -> Response<Body> {
+    // browsers send it with cross-origin requests, which a crawled page might make
+    if req.headers().contains_key(ORIGIN) {
+        return response(
+            StatusCode::FORBIDDEN,
+            "Requests from browsers are forbidden".to_owned(),
+        );
+    }
+
+    // a request is moved to read its body
+    let method = req.method().clone();
+    let path = req.uri().path().to_owned();
+    let command = match (&method, path.as_str()) {
+        (&Method::GET, "/statistics") => {
+            let json = stats.borrow().to_json().to_string();
+            return Response::builder()
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(json))
+                .unwrap();
+        }
+        (&Method::POST, "/pause") => Command::Pause,
+        (&Method::POST, "/resume") => Command::Resume,
+        (&Method::POST, "/stop") => Command::Stop,
+        (&Method::POST, "/seeds") => match read_body(req).await.map(|body| parse_seeds(&body)) {
+            Ok(Ok(urls)) => Command::AddSeeds(urls),
+            Ok(Err(err)) | Err(err) => return response(StatusCode::BAD_REQUEST, err),
+        },
+        (&Method::POST, "/limit") => match read_body(req).await.map(|body| parse_limit(&body)) {
+            Ok(Ok(limit)) => Command::SetLimit(limit),
+            Ok(Err(err)) | Err(err) => return response(StatusCode::BAD_REQUEST, err),
+        },
+        _ => return response(StatusCode::NOT_FOUND, "Unknown route".to_owned()),
+    };
+
+    match commands.send(command) {
+        Ok(()) => response(StatusCode::OK, "OK".to_owned()),
+        Err(..) => response(StatusCode::GONE, "A crawl is finished".to_owned()),
+    }
+}
+
+async fn read_body(req: Request<Body>) -> Result<String, String> {
+    let body = hyper::body::to_bytes(req.into_body())
+        .await
+        .map_err(|e| format!("Failed to read a body {}", e))?;
+    String::from_utf8(body.to_vec()).map_err(|e| format!("A body isn't UTF-8 {}", e))
+}
+
+fn parse_seeds(body: &str) -> Result<Vec<Url>, String> {
+    body.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| Url::parse(line).map_err(|e| format!("Failed to parse a url {} {}", line, e)))
+        .collect()
+}
+
+fn parse_limit(body: &str) -> Result<Option<usize>, String> {
+    let body = body.trim();
+    if body.is_empty() {
+        return Ok(None);
+    }
+
+    body.parse()
+        .map(Some)
+        .map_err(|e| format!("Failed to parse a limit {} {}", body, e))
+}
+
+fn response(status: StatusCode, text: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(text))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    #[tokio::test]
+    async fn commands() {
+        let (commands, mut receiver) = mpsc::unbounded_channel();
+        let stats = Statistics {
+            count_visited: 3,
+            ..Statistics::default()
+        };
+        let (_sender, stats) = watch::channel(stats);
+        let shutdown = Arc::new(Notify::new());
+        let addr = serve(
+            "127.0.0.1:0".parse().unwrap(),
+            commands,
+            stats,
+            shutdown.clone(),
+        )
+        .unwrap();
+
+        let client = reqwest::Client::new();
+        let post = |path: &str, body: &str| {
+            client
+                .post(format!("http://{}{}", addr, path))
+                .body(body.to_owned())
+                .send()
+        };
+
+        let resp = post("/pause", "").await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        assert_eq!(receiver.recv().await, Some(Command::Pause));
+
+        post("/seeds", "http://example.net\n\nhttp://example.org\n")
+            .await
+            .unwrap();
+        assert_eq!(
+            receiver.recv().await,
+            Some(Command::AddSeeds(vec![
+                Url::parse("http://example.net").unwrap(),
+                Url::parse("http://example.org").unwrap(),
+            ]))
+        );
+
+        let resp = post("/seeds", "not a url").await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
+
+        let resp = client
+            .post(format!("http://{}/stop", addr))
+            .header("Origin", "http://example.net")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::FORBIDDEN);
+
+        post("/limit", "10").await.unwrap();
+        assert_eq!(receiver.recv().await, Some(Command::SetLimit(Some(10))));
+
+        let stats = client
+            .get(format!("http://{}/statistics", addr))
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        let stats = serde_json::from_str::<Value>(&stats).unwrap();
+        assert_eq!(stats["visited"], 3);
+
+        drop(receiver);
+        let resp = post("/stop", "").await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::GONE);
+
+        shutdown.notify_one();
+    }
+
+    #[test]
+    fn limit() {
+        assert_eq!(parse_limit(" 5\n"), Ok(Some(5)));
+        assert_eq!(parse_limit(""), Ok(None));
+        assert!(parse_limit("five").is_err());
+    }
+}
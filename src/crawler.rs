@@ -0,0 +1,443 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A library interface of a crawl.
+//!
+//! A crawl is set up by [`Crawler::builder`] with any [`EngineBuilder`],
+//! so custom backends might be used in place of webdriver ones.
+//! Started crawl is controlled by a [`CrawlHandle`],
+//! which is awaited for collected data and statistics.
+
+use crate::{
+    backend::Backend,
+    control,
+    engine::RecyclePolicy,
+    engine_builder::EngineBuilder,
+    engine_ring::{
+        EngineRing, DEFAULT_BUILD_ATTEMPTS, DEFAULT_BUILD_BACKOFF, DEFAULT_MAX_REBUILDS,
+    },
+    filters::Filter,
+    frontier::Frontier,
+    metrics,
+    observer::CrawlObserver,
+    progress,
+    retry::{RetryPool, DEFAULT_MAX_DELAY},
+    robots::RobotsMap,
+    warc::WarcWriter,
+    workload::{Command, ErrorPolicy, RetryPolicy, Statistics, StopConditions, Workload},
+};
+use async_channel::Sender;
+use async_trait::async_trait;
+use log::{error, info};
+use serde_json::Value;
+use std::{
+    future::Future,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    sync::{mpsc, watch, Notify},
+    task::JoinHandle,
+};
+use url::Url;
+
+const DEFAULT_RETRY_THRESHOLD: Duration = Duration::from_secs(10);
+const DEFAULT_RETRY_COUNT: usize = 3;
+
+/// A destination of collected data.
+/// Data is written as soon as it's collected.
+/// A crawl waits for a write to finish,
+/// so a slow sink holds it back instead of losing data.
+#[async_trait]
+pub trait Sink: Send {
+    async fn write(&mut self, data: &Value) -> io::Result<()>;
+}
+
+/// A bounded channel which is full makes a crawl wait for a receiver.
+#[async_trait]
+impl Sink for Sender<Value> {
+    async fn write(&mut self, data: &Value) -> io::Result<()> {
+        self.send(data.clone())
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e.to_string()))
+    }
+}
+
+pub struct Crawler;
+
+impl Crawler {
+    pub fn builder<EB>(engine_builder: EB) -> CrawlerBuilder<EB> {
+        CrawlerBuilder::new(engine_builder)
+    }
+}
+
+pub struct CrawlerBuilder<EB> {
+    engine_builder: EB,
+    count_engines: usize,
+    max_rebuilds: usize,
+    build_attempts: usize,
+    build_backoff: Duration,
+    recycle: RecyclePolicy,
+    seeds: Vec<Url>,
+    filters: Vec<Filter>,
+    stop_conditions: StopConditions,
+    retry_policy: RetryPolicy,
+    retry_threshold: Duration,
+    retry_max_delay: Duration,
+    retry_count: usize,
+    error_policy: ErrorPolicy,
+    robots: Option<(String, RobotsMap)>,
+    warc: Option<WarcWriter>,
+    frontier: Option<Box<dyn Frontier>>,
+    sinks: Vec<Box<dyn Sink>>,
+    observers: Vec<Arc<dyn CrawlObserver>>,
+    metrics_addr: Option<SocketAddr>,
+    show_progress: bool,
+    control_addr: Option<SocketAddr>,
+    commands: mpsc::UnboundedSender<Command>,
+    command_receiver: mpsc::UnboundedReceiver<Command>,
+    stop: Arc<Notify>,
+}
+
+impl<EB> CrawlerBuilder<EB> {
+    fn new(engine_builder: EB) -> Self {
+        let (commands, command_receiver) = mpsc::unbounded_channel();
+        Self {
+            engine_builder,
+            count_engines: 1,
+            max_rebuilds: DEFAULT_MAX_REBUILDS,
+            build_attempts: DEFAULT_BUILD_ATTEMPTS,
+            build_backoff: DEFAULT_BUILD_BACKOFF,
+            recycle: RecyclePolicy::default(),
+            seeds: Vec::new(),
+            filters: Vec::new(),
+            stop_conditions: StopConditions::default(),
+            retry_policy: RetryPolicy::RetryFirst,
+            retry_threshold: DEFAULT_RETRY_THRESHOLD,
+            retry_max_delay: DEFAULT_MAX_DELAY,
+            retry_count: DEFAULT_RETRY_COUNT,
+            error_policy: ErrorPolicy::default(),
+            robots: None,
+            warc: None,
+            frontier: None,
+            sinks: Vec::new(),
+            observers: Vec::new(),
+            metrics_addr: None,
+            show_progress: false,
+            control_addr: None,
+            commands,
+            command_receiver,
+            stop: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Sets an amount of engines which work in parallel.
+    pub fn engines(mut self, count: usize) -> Self {
+        self.count_engines = count;
+        self
+    }
+
+    /// Sets an amount of times engines are rebuilt after their sessions are lost.
+    pub fn max_rebuilds(mut self, max_rebuilds: usize) -> Self {
+        self.max_rebuilds = max_rebuilds;
+        self
+    }
+
+    /// Sets an amount of attempts to build an engine
+    /// and a delay before a second attempt which is doubled on each next one.
+    pub fn build_retries(mut self, attempts: usize, backoff: Duration) -> Self {
+        self.build_attempts = attempts;
+        self.build_backoff = backoff;
+        self
+    }
+
+    pub fn recycle(mut self, recycle: RecyclePolicy) -> Self {
+        self.recycle = recycle;
+        self
+    }
+
+    /// Adds urls from which a crawl is started.
+    pub fn seeds(mut self, urls: impl IntoIterator<Item = Url>) -> Self {
+        self.seeds.extend(urls);
+        self
+    }
+
+    /// Adds a filter which seeds and found urls must pass.
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.filters.push(filter);
+        self
+    }
+
+    /// Stops a crawl after the amount of collected data, `null` data isn't counted.
+    /// Output is truncated to exactly the limit.
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.stop_conditions.max_collected = Some(limit);
+        self
+    }
+
+    /// Stops a crawl after the amount of visited pages including failed ones.
+    pub fn max_visited(mut self, max_visited: usize) -> Self {
+        self.stop_conditions.max_visited = Some(max_visited);
+        self
+    }
+
+    /// Stops a crawl after the time, pages which are being worked on are finished.
+    pub fn max_duration(mut self, max_duration: Duration) -> Self {
+        self.stop_conditions.max_duration = Some(max_duration);
+        self
+    }
+
+    /// Sets how failed urls are retried.
+    pub fn retry(mut self, policy: RetryPolicy, threshold: Duration, count: usize) -> Self {
+        self.retry_policy = policy;
+        self.retry_threshold = threshold;
+        self.retry_count = count;
+        self
+    }
+
+    /// Sets a max delay of a retry.
+    pub fn retry_max_delay(mut self, max_delay: Duration) -> Self {
+        self.retry_max_delay = max_delay;
+        self
+    }
+
+    pub fn error_policy(mut self, policy: ErrorPolicy) -> Self {
+        self.error_policy = policy;
+        self
+    }
+
+    /// Turns on a robots.txt check for a robot.
+    /// A client is used to fetch robots.txt files.
+    pub fn robots(mut self, robot: impl Into<String>, client: reqwest::Client) -> Self {
+        self.robots = Some((robot.into(), RobotsMap::new(client)));
+        self
+    }
+
+    /// Saves visited pages in WARC files.
+    pub fn warc(mut self, writer: WarcWriter) -> Self {
+        self.warc = Some(writer);
+        self
+    }
+
+    /// Sets a queue of urls and a store of seen ones.
+    /// By default both are kept in memory.
+    pub fn frontier(mut self, frontier: impl Frontier + 'static) -> Self {
+        self.frontier = Some(Box::new(frontier));
+        self
+    }
+
+    /// Adds a sink which receives data as soon as it's collected.
+    pub fn sink(mut self, sink: impl Sink + 'static) -> Self {
+        self.sinks.push(Box::new(sink));
+        self
+    }
+
+    /// Adds an observer of crawl events.
+    /// Observers are called in the order they're added.
+    pub fn observer(mut self, observer: impl CrawlObserver + 'static) -> Self {
+        self.observers.push(Arc::new(observer));
+        self
+    }
+
+    /// Serves Prometheus metrics of the crawl on the address while it runs.
+    pub fn metrics(mut self, addr: SocketAddr) -> Self {
+        self.metrics_addr = Some(addr);
+        self
+    }
+
+    /// Shows a live view of the crawl on stderr while it runs.
+    pub fn progress(mut self) -> Self {
+        self.show_progress = true;
+        self
+    }
+
+    /// Serves an HTTP endpoint on the address which controls the crawl while it runs.
+    /// See [`control`](crate::control) for its routes.
+    pub fn control(mut self, addr: SocketAddr) -> Self {
+        self.control_addr = Some(addr);
+        self
+    }
+
+    /// Sets a notification which stops a crawl the same way [`CrawlHandle::stop`] does.
+    pub fn stop_on(mut self, stop: Arc<Notify>) -> Self {
+        self.stop = stop;
+        self
+    }
+}
+
+impl<B, EB> CrawlerBuilder<EB>
+where
+    EB: EngineBuilder<Backend = B> + Send + Sync + 'static,
+    B: Backend + Send + 'static,
+{
+    /// Starts a crawl in a background task.
+    pub fn start(self) -> CrawlHandle {
+        let stop = self.stop.clone();
+        let commands = self.commands.clone();
+        let (sender, stats) = watch::channel(Statistics::default());
+        let task = tokio::spawn(self.run(sender, stats.clone()));
+
+        CrawlHandle {
+            task,
+            stop,
+            commands,
+            stats,
+        }
+    }
+
+    /// Runs a crawl to the end in the current task.
+    pub async fn run_to_end(self) -> (Vec<Value>, Statistics) {
+        let (sender, stats) = watch::channel(Statistics::default());
+        self.run(sender, stats).await
+    }
+
+    async fn run(
+        self,
+        sender: watch::Sender<Statistics>,
+        stats: watch::Receiver<Statistics>,
+    ) -> (Vec<Value>, Statistics) {
+        let mut ring = EngineRing::new(self.engine_builder, self.count_engines);
+        ring.set_max_rebuilds(self.max_rebuilds);
+        ring.set_build_retries(self.build_attempts, self.build_backoff);
+        ring.set_recycle_policy(self.recycle);
+        let mut retry_pool = RetryPool::new(self.retry_threshold, self.retry_count);
+        retry_pool.set_max_delay(self.retry_max_delay);
+        let (use_robots, robot, robots) = match self.robots {
+            Some((robot, robots)) => (true, robot, robots),
+            None => (false, String::new(), RobotsMap::default()),
+        };
+        let mut workload = Workload::new(
+            ring,
+            self.stop_conditions.clone(),
+            self.retry_policy,
+            retry_pool,
+            use_robots,
+            robot,
+            robots,
+        );
+        workload.set_error_policy(self.error_policy);
+        workload.set_filters(self.filters.clone());
+
+        if let Some(warc) = self.warc {
+            workload.set_warc_writer(warc);
+        }
+
+        if let Some(frontier) = self.frontier {
+            workload.set_frontier(frontier);
+        }
+
+        for sink in self.sinks {
+            workload.add_sink(sink);
+        }
+
+        for observer in self.observers {
+            workload.add_observer(observer);
+        }
+
+        workload.set_live_statistics(sender);
+        workload.set_control(self.command_receiver);
+
+        let control_shutdown = Arc::new(Notify::new());
+        if let Some(addr) = self.control_addr {
+            match control::serve(addr, self.commands, stats.clone(), control_shutdown.clone()) {
+                Ok(addr) => info!("Serving a control endpoint on {}", addr),
+                Err(err) => error!("Failed to serve a control endpoint on {}: {}", addr, err),
+            }
+        }
+
+        let metrics_shutdown = Arc::new(Notify::new());
+        if let Some(addr) = self.metrics_addr {
+            match metrics::serve(addr, stats.clone(), metrics_shutdown.clone()) {
+                Ok(addr) => info!("Serving metrics on {}", addr),
+                Err(err) => error!("Failed to serve metrics on {}: {}", addr, err),
+            }
+        }
+
+        let progress_shutdown = Arc::new(Notify::new());
+        let progress = if self.show_progress {
+            let limit = self.stop_conditions.max_collected;
+            Some(progress::show(stats, limit, progress_shutdown.clone()))
+        } else {
+            None
+        };
+
+        let mut seeds = self.seeds;
+        seeds.retain(|url| !self.filters.iter().any(|f| f.is_ignored(url)));
+
+        let output = workload.start(seeds, self.stop).await;
+        metrics_shutdown.notify_one();
+        control_shutdown.notify_one();
+
+        if let Some(progress) = progress {
+            // the last frame is drawn before anything else is printed
+            progress_shutdown.notify_one();
+            let _ = progress.await;
+        }
+
+        output
+    }
+}
+
+/// A handle of a running crawl.
+/// It resolves to collected data and final statistics.
+pub struct CrawlHandle {
+    task: JoinHandle<(Vec<Value>, Statistics)>,
+    stop: Arc<Notify>,
+    commands: mpsc::UnboundedSender<Command>,
+    stats: watch::Receiver<Statistics>,
+}
+
+impl CrawlHandle {
+    /// Stops a crawl.
+    /// Engines finish pages they work on so the handle resolves a bit later.
+    pub fn stop(&self) {
+        self.stop.notify_one();
+    }
+
+    /// Stops dispatching urls to engines until the crawl is resumed.
+    pub fn pause(&self) {
+        self.send(Command::Pause);
+    }
+
+    pub fn resume(&self) {
+        self.send(Command::Resume);
+    }
+
+    /// Adds urls to visit, ones which were already seen are ignored.
+    pub fn add_seeds(&self, urls: impl IntoIterator<Item = Url>) {
+        self.send(Command::AddSeeds(urls.into_iter().collect()));
+    }
+
+    /// Changes an amount of data to collect, `None` removes the limit.
+    pub fn set_limit(&self, limit: Option<usize>) {
+        self.send(Command::SetLimit(limit));
+    }
+
+    fn send(&self, command: Command) {
+        // a finished crawl ignores commands
+        let _ = self.commands.send(command);
+    }
+
+    /// Returns statistics of a crawl at the moment.
+    pub fn statistics(&self) -> Statistics {
+        self.stats.borrow().clone()
+    }
+}
+
+impl Future for CrawlHandle {
+    type Output = (Vec<Value>, Statistics);
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.task)
+            .poll(cx)
+            .map(|result| match result {
+                Ok(output) => output,
+                Err(err) => std::panic::resume_unwind(err.into_panic()),
+            })
+    }
+}
@@ -2,19 +2,109 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use crate::backend::{Backend, BackendError};
+use crate::artifacts;
+use crate::backend::{Backend, BackendError, ErrorClass, SearchResult};
 use crate::filters::Filter;
+use crate::observer::{CrawlObserver, FilterReason, Observers};
+use crate::proxy::ProxyRotation;
+use chrono::{DateTime, Utc};
 use log::info;
 use serde_json::Value;
+use std::time::{Duration, Instant};
 use url::Url;
 
 pub type EngineId = usize;
 
+/// A result of a page visit.
+#[derive(Debug, Clone)]
+pub struct Page {
+    pub url: Url,
+    /// Links found on the page which passed filters.
+    pub urls: Vec<Url>,
+    /// An output record.
+    /// It's `None` if the page was skipped.
+    pub data: Option<Value>,
+    pub source: Option<String>,
+    /// A body of the original response if a backend obtained it.
+    pub body: Option<String>,
+    pub status: Option<u16>,
+    pub headers: Vec<(String, String)>,
+    pub metrics: PageMetrics,
+}
+
+impl Page {
+    /// Checks whether a page has data other than `null`.
+    pub fn is_collected(&self) -> bool {
+        matches!(&self.data, Some(data) if !data.is_null())
+    }
+}
+
+/// Measurements of a page visit.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PageMetrics {
+    pub load_time: Option<Duration>,
+    pub script_time: Option<Duration>,
+    /// A size of a main document in bytes,
+    /// which is taken from a `Content-Length` header or a saved source.
+    pub size: Option<u64>,
+    /// An amount of links found on the page.
+    pub count_links: usize,
+    /// An amount of links which were dropped by filters.
+    pub count_filtered: usize,
+}
+
+/// A policy of handling pages which were loaded with a 4xx or 5xx status.
+/// Other statuses, such as a redirect which wasn't followed yet, are successes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HttpErrorPolicy {
+    Extract,
+    Skip,
+    Error,
+}
+
+impl Default for HttpErrorPolicy {
+    fn default() -> Self {
+        Self::Extract
+    }
+}
+
+/// Limits after which an engine's session is recreated.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RecyclePolicy {
+    pub pages: Option<usize>,
+    pub age: Option<Duration>,
+}
+
+impl RecyclePolicy {
+    /// Shortens limits proportionally to a position of an engine
+    /// so engines started together are recycled one by one.
+    pub(crate) fn staggered(&self, position: usize, count: usize) -> Self {
+        let part = |value: u128| value * (position as u128 + 1) / count as u128;
+        Self {
+            pages: self
+                .pages
+                .map(|pages| (part(pages as u128) as usize).max(1)),
+            age: self
+                .age
+                .map(|age| Duration::from_millis(part(age.as_millis()) as u64)),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Engine<B> {
     pub(crate) id: EngineId,
     pub(crate) filters: Vec<Filter>,
+    pub(crate) http_errors: HttpErrorPolicy,
+    pub(crate) recycle: RecyclePolicy,
     pub(crate) backend: B,
+    /// A name of a proxy a session is bound to.
+    pub(crate) proxy: Option<String>,
+    proxy_rotation: ProxyRotation,
+    pub(crate) observers: Observers,
+    count_pages: usize,
+    count_blocks: usize,
+    started: Instant,
 }
 
 impl<B: Backend> Engine<B> {
@@ -23,15 +113,107 @@ impl<B: Backend> Engine<B> {
             id,
             backend,
             filters: filters.to_vec(),
+            http_errors: HttpErrorPolicy::default(),
+            recycle: RecyclePolicy::default(),
+            proxy: None,
+            proxy_rotation: ProxyRotation::default(),
+            observers: Observers::default(),
+            count_pages: 0,
+            count_blocks: 0,
+            started: Instant::now(),
         }
     }
 
-    pub async fn run(&mut self, url: Url) -> Result<(Vec<Url>, Value), BackendError> {
+    pub fn with_proxy(mut self, proxy: Option<String>, rotation: ProxyRotation) -> Self {
+        self.proxy = proxy;
+        self.proxy_rotation = rotation;
+        self
+    }
+
+    /// Checks whether the last page was blocked.
+    pub fn is_blocked(&self) -> bool {
+        self.count_blocks > 0
+    }
+
+    pub fn with_http_errors(mut self, policy: HttpErrorPolicy) -> Self {
+        self.http_errors = policy;
+        self
+    }
+
+    /// Checks whether an engine reached limits of its recycle policy
+    /// or it's time to move it to another proxy.
+    pub fn is_worn_out(&self) -> bool {
+        self.recycle
+            .pages
+            .map_or(false, |pages| self.count_pages >= pages)
+            || self
+                .recycle
+                .age
+                .map_or(false, |age| self.started.elapsed() >= age)
+            || (self.proxy.is_some() && self.is_proxy_exhausted())
+    }
+
+    fn is_proxy_exhausted(&self) -> bool {
+        self.proxy_rotation
+            .pages
+            .map_or(false, |pages| self.count_pages >= pages)
+            || self
+                .proxy_rotation
+                .blocks
+                .map_or(false, |blocks| self.count_blocks >= blocks)
+    }
+
+    pub async fn run(&mut self, url: Url) -> Result<Page, BackendError> {
         info!("engine {} working on {}", self.id, url);
 
-        let result = self.backend.search(&url).await?;
+        self.count_pages += 1;
+
+        let result = self.backend.search(&url).await;
+        if is_blocked(&result) {
+            self.count_blocks += 1;
+        } else {
+            self.count_blocks = 0;
+        }
+        let result = result?;
+
+        if let Some(status) = result.status.filter(|status| *status >= 400) {
+            match self.http_errors {
+                // a server asked to come back later so it's left to error rules
+                _ if is_temporary_status(status) => {
+                    return Err(BackendError::HttpStatus {
+                        address: url,
+                        status,
+                        retry_after: retry_after(&result.headers),
+                    })
+                }
+                HttpErrorPolicy::Extract => (),
+                HttpErrorPolicy::Skip => {
+                    info!("engine {} skips {} with status {}", self.id, url, status);
+
+                    let metrics = page_metrics(&result, 0);
+                    return Ok(Page {
+                        url,
+                        urls: Vec::new(),
+                        data: None,
+                        source: result.source,
+                        body: result.body,
+                        status: result.status,
+                        headers: result.headers,
+                        metrics,
+                    });
+                }
+                HttpErrorPolicy::Error => {
+                    return Err(BackendError::HttpStatus {
+                        address: url,
+                        status,
+                        retry_after: retry_after(&result.headers),
+                    })
+                }
+            }
+        }
+
         let found_urls = result.urls.len();
-        let urls = self.filter_result(&result.urls, &url);
+        let urls = self.filter_result(&result.urls, &url).await;
 
         info!(
             "engine {} found {} urls and filtered {}",
@@ -40,20 +222,93 @@ impl<B: Backend> Engine<B> {
             found_urls - urls.len()
         );
 
-        Ok((urls, result.data))
+        let metrics = page_metrics(&result, found_urls - urls.len());
+        let data = artifacts::record(&url, result.data, result.artifacts.as_deref());
+
+        Ok(Page {
+            url,
+            urls,
+            data: Some(data),
+            source: result.source,
+            body: result.body,
+            status: result.status,
+            headers: result.headers,
+            metrics,
+        })
+    }
+
+    async fn filter_result(&mut self, urls: &[String], url: &Url) -> Vec<Url> {
+        let (urls, ignored) = validate_links(url, urls, &self.filters);
+        for url in &ignored {
+            if let Some(filter) = self.filters.iter().find(|f| f.is_ignored(url)) {
+                self.observers
+                    .on_url_filtered(url, FilterReason::Filter(filter))
+                    .await;
+            }
+        }
+
+        urls
     }
+}
+
+/// Parses a `Retry-After` header which is either a number of seconds or an HTTP date.
+fn retry_after(headers: &[(String, String)]) -> Option<Duration> {
+    let (_, value) = headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("retry-after"))?;
+    let value = value.trim();
+
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    let delay = date.with_timezone(&Utc) - Utc::now();
+    // a date in the past means the request can be retried right away
+    Some(delay.to_std().unwrap_or_default())
+}
+
+fn page_metrics(result: &SearchResult, count_filtered: usize) -> PageMetrics {
+    let size = result
+        .headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse().ok())
+        .or_else(|| result.source.as_ref().map(|source| source.len() as u64));
+
+    PageMetrics {
+        load_time: result.load_time,
+        script_time: result.script_time,
+        size,
+        count_links: result.urls.len(),
+        count_filtered,
+    }
+}
 
-    fn filter_result(&mut self, urls: &[String], url: &Url) -> Vec<Url> {
-        validate_links(url, urls, &self.filters)
+/// Checks whether a status means a page is unavailable for a while,
+/// which is an error whatever a policy of HTTP errors is.
+fn is_temporary_status(status: u16) -> bool {
+    matches!(status, 429 | 503)
+}
+
+/// A status is checked only if a backend reported it,
+/// otherwise only errors are taken into account.
+fn is_blocked(result: &Result<SearchResult, BackendError>) -> bool {
+    match result {
+        Ok(result) => matches!(result.status, Some(403) | Some(429)),
+        Err(err) => matches!(
+            err.class(),
+            ErrorClass::Timeout | ErrorClass::ConnectionRefused
+        ),
     }
 }
 
-fn validate_links(base: &Url, links: &[String], filters: &[Filter]) -> Vec<Url> {
+/// Makes links absolute and splits them into valid and ignored by filters ones.
+fn validate_links(base: &Url, links: &[String], filters: &[Filter]) -> (Vec<Url>, Vec<Url>) {
     links
         .iter()
         .filter_map(|link| make_absolute_url(base, &link))
-        .filter(|l| !filters.iter().any(|f| f.is_ignored(l)))
-        .collect()
+        .partition(|l| !filters.iter().any(|f| f.is_ignored(l)))
 }
 
 fn make_absolute_url(base: &Url, url: &str) -> Option<Url> {
@@ -69,9 +324,49 @@ fn make_absolute_url(base: &Url, url: &str) -> Option<Url> {
 
 #[cfg(test)]
 mod tests {
-    use super::validate_links;
+    use super::{retry_after, validate_links, RecyclePolicy};
+    use std::time::{Duration, Instant};
     use url::Url;
 
+    #[test]
+    fn staggered_recycle_policy() {
+        let policy = RecyclePolicy {
+            pages: Some(100),
+            age: Some(Duration::from_secs(60)),
+        };
+
+        assert_eq!(
+            policy.staggered(0, 4),
+            RecyclePolicy {
+                pages: Some(25),
+                age: Some(Duration::from_secs(15)),
+            }
+        );
+        assert_eq!(policy.staggered(3, 4), policy);
+        assert_eq!(
+            RecyclePolicy {
+                pages: Some(1),
+                age: None,
+            }
+            .staggered(0, 4)
+            .pages,
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn parse_retry_after() {
+        let header = |value: &str| vec![("Retry-After".to_owned(), value.to_owned())];
+
+        assert_eq!(retry_after(&header("120")), Some(Duration::from_secs(120)));
+        assert_eq!(
+            retry_after(&header("Wed, 21 Oct 2015 07:28:00 GMT")),
+            Some(Duration::default())
+        );
+        assert_eq!(retry_after(&header("soon")), None);
+        assert_eq!(retry_after(&[]), None);
+    }
+
     #[test]
     fn validate_link() {
         assert_eq!(
@@ -85,12 +380,15 @@ mod tests {
                 ],
                 &[]
             ),
-            vec![
-                Url::parse("https://example_1.net").unwrap(),
-                Url::parse("https://example_1.net?p1=123&p2=asd").unwrap(),
-                Url::parse("https://example.net/path").unwrap(),
-                Url::parse("https://example.net/path?p1=123&p2=asd").unwrap(),
-            ]
+            (
+                vec![
+                    Url::parse("https://example_1.net").unwrap(),
+                    Url::parse("https://example_1.net?p1=123&p2=asd").unwrap(),
+                    Url::parse("https://example.net/path").unwrap(),
+                    Url::parse("https://example.net/path?p1=123&p2=asd").unwrap(),
+                ],
+                Vec::new()
+            )
         )
     }
 }
@@ -3,12 +3,26 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use crate::{
+    artifacts::ArtifactsConfig,
     backend::{SideRunner, WebDriverSearcher},
-    engine::Engine,
+    engine::{Engine, HttpErrorPolicy},
+    extension::cdp,
     filters::Filter,
+    proxy::ProxyPool,
+    replay::{Archive, ReplayBackend},
 };
 use async_trait::async_trait;
-use std::{fmt::Display, io, time::Duration};
+use log::warn;
+use serde_json::{json, Map, Value};
+use std::{
+    fmt::Display,
+    io,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 use thirtyfour::{
     prelude::WebDriverResult, Capabilities, DesiredCapabilities, WebDriver, WebDriverCommands,
 };
@@ -18,28 +32,99 @@ use url::Url;
 pub trait EngineBuilder {
     type Backend;
 
-    async fn build(&mut self) -> io::Result<Engine<Self::Backend>>;
+    /// Webdriver endpoints on which engines might be built.
+    fn endpoints(&self) -> Vec<Endpoint>;
+
+    /// Builds an engine on a given endpoint.
+    /// It might be called concurrently to start a few engines at once.
+    async fn build(&self, endpoint: &Url) -> io::Result<Engine<Self::Backend>>;
 }
 
 pub struct WebDriverEngineBuilder {
     config: WebDriverConfig,
     code: String,
     filters: Vec<Filter>,
-    id: usize,
+    artifacts: ArtifactsConfig,
+    id: AtomicUsize,
 }
 
 #[derive(Debug, Clone)]
 pub struct WebDriverConfig {
     pub load_timeout: Duration,
     pub browser: Browser,
-    pub webdriver_address: Url,
-    pub proxy: Option<Proxy>,
+    pub endpoints: Vec<Endpoint>,
+    pub proxies: Arc<ProxyPool>,
+    pub user_agent: String,
+    pub accept_language: Option<String>,
+    pub headers: Vec<(String, String)>,
+    pub http_errors: HttpErrorPolicy,
+    /// Runs browsers without a window.
+    pub headless: bool,
+    /// Capabilities which are merged into default ones.
+    pub capabilities: Option<Value>,
+}
+
+impl WebDriverConfig {
+    /// Headers which are set by us on each request.
+    pub fn request_headers(&self) -> Vec<(String, String)> {
+        let mut headers = vec![("User-Agent".to_owned(), self.user_agent.clone())];
+        if let Some(lang) = self.accept_language.as_ref() {
+            headers.push(("Accept-Language".to_owned(), lang.clone()));
+        }
+        headers.extend(self.headers.iter().cloned());
+
+        headers
+    }
+
+    /// Checks whether browsers are allowed to accept invalid certificates
+    /// by custom capabilities.
+    pub fn accepts_insecure_certs(&self) -> bool {
+        self.capabilities
+            .as_ref()
+            .and_then(|caps| caps.get("acceptInsecureCerts"))
+            .and_then(Value::as_bool)
+            .unwrap_or(false)
+    }
+}
+
+/// A webdriver hub or node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Endpoint {
+    pub address: Url,
+    /// A max amount of sessions which might be open on the endpoint.
+    pub capacity: Option<usize>,
+    /// A share of engines placed on the endpoint relatively to other endpoints.
+    pub weight: usize,
+}
+
+impl Endpoint {
+    pub fn new(address: Url) -> Self {
+        Self {
+            address,
+            capacity: None,
+            weight: 1,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub enum Browser {
     Firefox,
     Chrome,
+    Edge,
+    /// Any W3C compatible driver which is set up by a capabilities file.
+    Generic,
+}
+
+impl Browser {
+    /// Returns a vendor prefix of Chrome DevTools Protocol commands if a driver supports them.
+    pub fn cdp_vendor(&self) -> Option<&'static str> {
+        match self {
+            Self::Chrome => Some("goog"),
+            Self::Edge => Some("ms"),
+            Self::Firefox | Self::Generic => None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -63,12 +148,18 @@ pub enum ManualProxy {
 }
 
 impl WebDriverEngineBuilder {
-    pub fn new(config: WebDriverConfig, code: String, filters: Vec<Filter>) -> Self {
+    pub fn new(
+        config: WebDriverConfig,
+        code: String,
+        filters: Vec<Filter>,
+        artifacts: ArtifactsConfig,
+    ) -> Self {
         Self {
             config,
             code,
             filters,
-            id: 0,
+            artifacts,
+            id: AtomicUsize::new(0),
         }
     }
 }
@@ -77,62 +168,204 @@ impl WebDriverEngineBuilder {
 impl EngineBuilder for WebDriverEngineBuilder {
     type Backend = WebDriverSearcher;
 
-    async fn build(&mut self) -> io::Result<Engine<Self::Backend>> {
-        let wb = create_webdriver(&self.config)
+    fn endpoints(&self) -> Vec<Endpoint> {
+        self.config.endpoints.clone()
+    }
+
+    async fn build(&self, endpoint: &Url) -> io::Result<Engine<Self::Backend>> {
+        let (wb, proxy) = create_webdriver(&self.config, endpoint)
             .await
             .map_err(|e| wrap_err("Failed to create a webdriver", e))?;
-        let searcher = WebDriverSearcher::new(wb, self.code.clone());
-        let id = self.id;
-        self.id += 1;
-        let engine = Engine::new(id, searcher, &self.filters);
+        let searcher = WebDriverSearcher::new(
+            wb,
+            self.code.clone(),
+            self.config.browser.clone(),
+            self.artifacts.clone(),
+        );
+        let id = self.id.fetch_add(1, Ordering::Relaxed);
+        let engine = Engine::new(id, searcher, &self.filters)
+            .with_http_errors(self.config.http_errors)
+            .with_proxy(proxy, self.config.proxies.rotation());
 
         Ok(engine)
     }
 }
 
-async fn create_webdriver(cfg: &WebDriverConfig) -> WebDriverResult<WebDriver> {
-    let driver = match cfg.browser {
+/// Creates a session with a proxy assigned from a pool.
+/// It returns the session and a name of the proxy.
+pub(crate) async fn create_webdriver(
+    cfg: &WebDriverConfig,
+    endpoint: &Url,
+) -> WebDriverResult<(WebDriver, Option<String>)> {
+    let proxy = cfg.proxies.assign();
+    let mut caps = desired_capabilities(cfg, proxy.as_ref().map(|p| &p.proxy))?;
+    if let Some(custom) = cfg.capabilities.as_ref() {
+        merge_capabilities(&mut caps, custom);
+    }
+
+    let driver =
+        WebDriver::new_with_timeout(endpoint.as_str(), &caps, Some(cfg.load_timeout)).await?;
+
+    driver.set_page_load_timeout(cfg.load_timeout).await?;
+    set_extra_headers(&driver, cfg).await?;
+
+    Ok((driver, proxy.map(|p| p.name)))
+}
+
+fn desired_capabilities(cfg: &WebDriverConfig, proxy: Option<&Proxy>) -> WebDriverResult<Value> {
+    let caps = match cfg.browser {
         Browser::Firefox => {
             let mut cops = DesiredCapabilities::firefox();
-            cops.set_headless()?;
+            if cfg.headless {
+                cops.set_headless()?;
+            }
             // by this option we try to resolve CAPTCHAs
             cops.add("unhandledPromptBehavior", "accept")?;
 
-            if let Some(p) = cfg.proxy.as_ref() {
-                let proxy = convert_proxy(p);
-                cops.set_proxy(proxy)?;
+            let mut prefs = Map::new();
+            prefs.insert(
+                "general.useragent.override".to_owned(),
+                json!(cfg.user_agent),
+            );
+            if let Some(lang) = cfg.accept_language.as_ref() {
+                prefs.insert("intl.accept_languages".to_owned(), json!(lang));
             }
+            cops.add_subkey("moz:firefoxOptions", "prefs", Value::Object(prefs))?;
 
-            WebDriver::new_with_timeout(
-                cfg.webdriver_address.as_str(),
-                &cops,
-                Some(cfg.load_timeout),
-            )
-            .await?
+            if let Some(p) = proxy {
+                cops.set_proxy(convert_proxy(p))?;
+            }
+
+            cops.get().clone()
         }
         Browser::Chrome => {
             let mut cops = DesiredCapabilities::chrome();
-            cops.set_headless()?;
+            if cfg.headless {
+                cops.set_headless()?;
+            }
             // by this option we try to resolve CAPTCHAs
             cops.add("unhandledPromptBehavior", "accept")?;
 
-            if let Some(p) = cfg.proxy.as_ref() {
-                let proxy = convert_proxy(p);
-                cops.set_proxy(proxy)?;
+            cops.add_chrome_arg(&format!("--user-agent={}", cfg.user_agent))?;
+            // performance logs are used to obtain a status and headers of a page
+            cops.add("goog:loggingPrefs", json!({ "performance": "ALL" }))?;
+            if let Some(lang) = cfg.accept_language.as_ref() {
+                cops.add_chrome_arg(&format!("--lang={}", lang))?;
+                cops.add_subkey(
+                    "goog:chromeOptions",
+                    "prefs",
+                    json!({ "intl.accept_languages": lang }),
+                )?;
             }
 
-            WebDriver::new_with_timeout(
-                cfg.webdriver_address.as_str(),
-                &cops,
-                Some(cfg.load_timeout),
-            )
-            .await?
+            if let Some(p) = proxy {
+                cops.set_proxy(convert_proxy(p))?;
+            }
+
+            cops.get().clone()
+        }
+        Browser::Edge => {
+            let mut cops = DesiredCapabilities::edge();
+            // by this option we try to resolve CAPTCHAs
+            cops.add("unhandledPromptBehavior", "accept")?;
+
+            let mut args = vec![format!("--user-agent={}", cfg.user_agent)];
+            if cfg.headless {
+                args.push("--headless".to_owned());
+            }
+            if let Some(lang) = cfg.accept_language.as_ref() {
+                args.push(format!("--lang={}", lang));
+                cops.add_subkey(
+                    "ms:edgeOptions",
+                    "prefs",
+                    json!({ "intl.accept_languages": lang }),
+                )?;
+            }
+            cops.add_subkey("ms:edgeOptions", "args", args)?;
+
+            if let Some(p) = proxy {
+                cops.set_proxy(convert_proxy(p))?;
+            }
+
+            cops.get().clone()
         }
+        // a generic driver is described only by a capabilities file
+        Browser::Generic => json!({}),
     };
 
-    driver.set_page_load_timeout(cfg.load_timeout).await?;
+    Ok(caps)
+}
 
-    Ok(driver)
+/// Merges custom capabilities into default ones.
+/// Objects are merged recursively and browser arguments are appended,
+/// while other values are replaced.
+fn merge_capabilities(caps: &mut Value, custom: &Value) {
+    match (caps, custom) {
+        (Value::Object(caps), Value::Object(custom)) => {
+            for (key, value) in custom {
+                match (key.as_str(), caps.get_mut(key), value) {
+                    ("args", Some(Value::Array(args)), Value::Array(custom)) => {
+                        merge_args(args, custom)
+                    }
+                    (_, Some(cap), _) => merge_capabilities(cap, value),
+                    (_, None, _) => {
+                        caps.insert(key.clone(), value.clone());
+                    }
+                }
+            }
+        }
+        (caps, custom) => *caps = custom.clone(),
+    }
+}
+
+/// Appends custom arguments to default ones.
+/// A custom argument replaces a default one with the same flag,
+/// e.g. `--user-agent=bot` replaces `--user-agent=DoonopRobot`.
+fn merge_args(args: &mut Vec<Value>, custom: &[Value]) {
+    let flag = |arg: &Value| {
+        arg.as_str()
+            .map(|arg| arg.split('=').next().unwrap_or(arg).to_owned())
+    };
+    for arg in custom {
+        if let Some(name) = flag(arg) {
+            args.retain(|arg| flag(arg).as_ref() != Some(&name));
+        }
+
+        args.push(arg.clone());
+    }
+}
+
+async fn set_extra_headers(driver: &WebDriver, cfg: &WebDriverConfig) -> WebDriverResult<()> {
+    if cfg.headers.is_empty() {
+        return Ok(());
+    }
+
+    match cfg.browser.cdp_vendor() {
+        Some(vendor) => {
+            let headers = cfg
+                .headers
+                .iter()
+                .map(|(name, value)| (name.clone(), json!(value)))
+                .collect::<Map<_, _>>();
+
+            cdp(driver, vendor, "Network.enable", json!({})).await?;
+            cdp(
+                driver,
+                vendor,
+                "Network.setExtraHTTPHeaders",
+                json!({ "headers": headers }),
+            )
+            .await?;
+        }
+        None => {
+            warn!(
+                "{:?} doesn't support extra headers; they're used only for auxiliary requests",
+                cfg.browser
+            );
+        }
+    }
+
+    Ok(())
 }
 
 fn convert_proxy(p: &Proxy) -> thirtyfour::Proxy {
@@ -179,16 +412,23 @@ pub struct SideRunnerEngineBuilder {
     config: WebDriverConfig,
     code: String,
     filters: Vec<Filter>,
-    id: usize,
+    artifacts: ArtifactsConfig,
+    id: AtomicUsize,
 }
 
 impl SideRunnerEngineBuilder {
-    pub fn new(config: WebDriverConfig, code: String, filters: Vec<Filter>) -> Self {
+    pub fn new(
+        config: WebDriverConfig,
+        code: String,
+        filters: Vec<Filter>,
+        artifacts: ArtifactsConfig,
+    ) -> Self {
         Self {
             config,
             code,
             filters,
-            id: 0,
+            artifacts,
+            id: AtomicUsize::new(0),
         }
     }
 }
@@ -197,18 +437,118 @@ impl SideRunnerEngineBuilder {
 impl EngineBuilder for SideRunnerEngineBuilder {
     type Backend = SideRunner;
 
-    async fn build(&mut self) -> io::Result<Engine<Self::Backend>> {
-        let wb = create_webdriver(&self.config)
+    fn endpoints(&self) -> Vec<Endpoint> {
+        self.config.endpoints.clone()
+    }
+
+    async fn build(&self, endpoint: &Url) -> io::Result<Engine<Self::Backend>> {
+        let (wb, proxy) = create_webdriver(&self.config, endpoint)
             .await
             .map_err(|e| wrap_err("Failed to create a webdriver", e))?;
 
         let file = siderunner::parse(std::io::Cursor::new(self.code.clone()))
             .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e)))?;
-        let searcher = SideRunner::new(wb, file);
-        let id = self.id;
-        self.id += 1;
-        let engine = Engine::new(id, searcher, &self.filters);
+        let searcher = SideRunner::new(
+            wb,
+            file,
+            self.config.browser.clone(),
+            self.artifacts.clone(),
+        );
+        let id = self.id.fetch_add(1, Ordering::Relaxed);
+        let engine = Engine::new(id, searcher, &self.filters)
+            .with_http_errors(self.config.http_errors)
+            .with_proxy(proxy, self.config.proxies.rotation());
 
         Ok(engine)
     }
 }
+
+pub struct ReplayEngineBuilder {
+    config: WebDriverConfig,
+    code: String,
+    filters: Vec<Filter>,
+    archive: Arc<Archive>,
+    id: AtomicUsize,
+}
+
+impl ReplayEngineBuilder {
+    pub fn new(
+        config: WebDriverConfig,
+        code: String,
+        filters: Vec<Filter>,
+        archive: Arc<Archive>,
+    ) -> Self {
+        Self {
+            config,
+            code,
+            filters,
+            archive,
+            id: AtomicUsize::new(0),
+        }
+    }
+}
+
+#[async_trait]
+impl EngineBuilder for ReplayEngineBuilder {
+    type Backend = ReplayBackend;
+
+    fn endpoints(&self) -> Vec<Endpoint> {
+        self.config.endpoints.clone()
+    }
+
+    async fn build(&self, endpoint: &Url) -> io::Result<Engine<Self::Backend>> {
+        let (wb, proxy) = create_webdriver(&self.config, endpoint)
+            .await
+            .map_err(|e| wrap_err("Failed to create a webdriver", e))?;
+        let searcher = ReplayBackend::new(wb, self.code.clone(), self.archive.clone());
+        let id = self.id.fetch_add(1, Ordering::Relaxed);
+        let engine = Engine::new(id, searcher, &self.filters)
+            .with_http_errors(self.config.http_errors)
+            .with_proxy(proxy, self.config.proxies.rotation());
+
+        Ok(engine)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::merge_capabilities;
+    use serde_json::json;
+
+    #[test]
+    fn merge_custom_capabilities() {
+        let mut caps = json!({
+            "browserName": "chrome",
+            "goog:chromeOptions": {
+                "args": ["--headless", "--user-agent=DoonopRobot"],
+                "prefs": { "intl.accept_languages": "en" },
+            },
+        });
+
+        merge_capabilities(
+            &mut caps,
+            &json!({
+                "goog:chromeOptions": {
+                    "args": ["--window-size=1920,1080", "--user-agent=bot"],
+                    "prefs": { "profile.managed_default_content_settings.images": 2 },
+                },
+                "acceptInsecureCerts": true,
+            }),
+        );
+
+        assert_eq!(
+            caps,
+            json!({
+                "browserName": "chrome",
+                "goog:chromeOptions": {
+                    "args": ["--headless", "--window-size=1920,1080", "--user-agent=bot"],
+                    "prefs": {
+                        "intl.accept_languages": "en",
+                        "profile.managed_default_content_settings.images": 2,
+                    },
+                },
+                "acceptInsecureCerts": true,
+            })
+        );
+    }
+}
@@ -3,56 +3,278 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use crate::{
-    engine::{Engine, EngineId},
-    engine_builder::EngineBuilder,
+    engine::{Engine, EngineId, RecyclePolicy},
+    engine_builder::{Endpoint, EngineBuilder},
 };
-use std::{collections::HashSet, io};
+use futures::future::{join_all, BoxFuture};
+use log::{error, warn};
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use url::Url;
+
+pub const DEFAULT_MAX_REBUILDS: usize = 10;
+pub const DEFAULT_BUILD_ATTEMPTS: usize = 3;
+pub const DEFAULT_BUILD_BACKOFF: Duration = Duration::from_secs(1);
+/// A time during which an endpoint which refused a session is avoided.
+const REFUSAL_COOLDOWN: Duration = Duration::from_secs(30);
 
 #[derive(Debug)]
 pub struct EngineRing<B, EB> {
     free_list: Vec<Engine<B>>,
     usage_list: HashSet<EngineId>,
     cap: usize,
-    builder: EB,
+    builder: Arc<EB>,
+    placement: Arc<Placement>,
+    /// Endpoints on which engines are placed.
+    placed: HashMap<EngineId, usize>,
+    max_rebuilds: usize,
+    count_rebuilds: usize,
+    recycle: RecyclePolicy,
+    count_built: usize,
+    build_attempts: usize,
+    build_backoff: Duration,
+    count_failed_builds: usize,
+    /// An amount of engines which are being built in background.
+    count_building: usize,
+}
+
+/// An engine which was built in background and isn't placed in a ring yet.
+pub struct BuiltEngine<B> {
+    engine: Engine<B>,
+    endpoint: usize,
 }
 
 impl<B, EB> EngineRing<B, EB>
 where
-    EB: EngineBuilder<Backend = B>,
+    EB: EngineBuilder<Backend = B> + Send + Sync + 'static,
 {
-    pub fn new(builder: EB, cap: usize) -> Self {
+    pub fn new(builder: EB, mut cap: usize) -> Self {
+        let placement = Placement::new(builder.endpoints());
+        if let Some(capacity) = placement.capacity().filter(|capacity| *capacity < cap) {
+            warn!(
+                "Endpoints have capacity only for {} engines out of {}",
+                capacity, cap
+            );
+            cap = capacity;
+        }
+
         Self {
             cap,
-            builder,
+            builder: Arc::new(builder),
+            placement: Arc::new(placement),
+            placed: HashMap::new(),
             free_list: Vec::new(),
             usage_list: HashSet::new(),
+            max_rebuilds: DEFAULT_MAX_REBUILDS,
+            count_rebuilds: 0,
+            recycle: RecyclePolicy::default(),
+            count_built: 0,
+            build_attempts: DEFAULT_BUILD_ATTEMPTS,
+            build_backoff: DEFAULT_BUILD_BACKOFF,
+            count_failed_builds: 0,
+            count_building: 0,
         }
     }
 
-    pub async fn obtain(&mut self) -> io::Result<Engine<B>> {
-        if let Some(engine) = self.free_list.pop() {
-            self.usage_list.insert(engine.id);
+    /// Sets an amount of attempts to build an engine
+    /// and a delay before a second attempt which is doubled on each next one.
+    pub fn set_build_retries(&mut self, attempts: usize, backoff: Duration) {
+        self.build_attempts = attempts.max(1);
+        self.build_backoff = backoff;
+    }
+
+    /// Sets limits after which engines are recycled.
+    pub fn set_recycle_policy(&mut self, recycle: RecyclePolicy) {
+        self.recycle = recycle;
+    }
+
+    /// Sets a limit of engines which might be rebuilt during a crawl.
+    pub fn set_max_rebuilds(&mut self, max_rebuilds: usize) {
+        self.max_rebuilds = max_rebuilds;
+    }
+
+    /// Builds engines concurrently to fill the ring.
+    /// The ring shrinks to an amount of engines which managed to start,
+    /// it's an error only if none did.
+    pub async fn start(&mut self) -> io::Result<usize> {
+        let count = self.cap - self.usage_list.len() - self.free_list.len();
+        let results = join_all((0..count).map(|_| self.build())).await;
+
+        let mut engines = Vec::new();
+        let mut last_err = None;
+        for result in results {
+            match result {
+                Ok((engine, endpoint)) => {
+                    self.placed.insert(engine.id, endpoint);
+                    engines.push(engine);
+                }
+                Err(err) => {
+                    error!("Failed to start an engine {}", err);
+                    self.count_failed_builds += 1;
+                    last_err = Some(err);
+                }
+            }
+        }
+
+        if engines.len() < count {
+            warn!("Only {} of {} engines started", engines.len(), count);
+            self.cap -= count - engines.len();
+        }
+
+        if let (0, Some(err)) = (self.cap, last_err) {
+            return Err(err);
+        }
+
+        let count_started = engines.len();
+        // engines are obtained in the order they were built
+        for (i, engine) in engines.into_iter().enumerate().rev() {
+            let engine = self.prepare(engine, i, count_started);
+            self.free_list.push(engine);
+        }
+
+        Ok(count_started)
+    }
+
+    /// Returns a free engine or builds a new one.
+    /// If an engine can't be built the ring shrinks.
+    pub async fn obtain(&mut self) -> io::Result<Engine<B>>
+    where
+        B: 'static,
+    {
+        if let Some(engine) = self.take_free() {
             return Ok(engine);
         }
 
-        if self.usage_list.len() >= self.cap {
+        let build = self.build_detached();
+        self.finish_build(build.await)
+    }
+
+    /// Returns a free engine if there's one.
+    pub fn take_free(&mut self) -> Option<Engine<B>> {
+        let engine = self.free_list.pop()?;
+        self.usage_list.insert(engine.id);
+        Some(engine)
+    }
+
+    /// Returns a future which builds a new engine and which doesn't borrow the ring,
+    /// so it might be run in background while the ring is used.
+    /// Its result must be passed to [`EngineRing::finish_build`].
+    pub fn build_detached(&mut self) -> BoxFuture<'static, io::Result<BuiltEngine<B>>>
+    where
+        B: 'static,
+    {
+        if self.usage_list.len() + self.count_building >= self.cap {
             panic!(
                 "WBRing cap is reached; mustn't never happen as we spawn N engines for N drivers"
             );
         }
 
-        let id = self.usage_list.len();
-        let engine = self.builder.build().await?;
-        self.usage_list.insert(id);
+        self.count_building += 1;
+        let builder = self.builder.clone();
+        let placement = self.placement.clone();
+        let attempts = self.build_attempts;
+        let backoff = self.build_backoff;
+
+        Box::pin(async move {
+            let (engine, endpoint) = build(&*builder, &placement, attempts, backoff).await?;
+            Ok(BuiltEngine { engine, endpoint })
+        })
+    }
+
+    /// Places an engine which was built in background in the ring.
+    /// If it failed to be built the ring shrinks.
+    pub fn finish_build(&mut self, result: io::Result<BuiltEngine<B>>) -> io::Result<Engine<B>> {
+        self.count_building -= 1;
+        let BuiltEngine { engine, endpoint } = match result {
+            Ok(built) => built,
+            Err(err) => {
+                self.count_failed_builds += 1;
+                self.cap -= 1;
+                return Err(err);
+            }
+        };
+
+        self.placed.insert(engine.id, endpoint);
+        let engine = self.prepare(engine, self.count_built, self.cap);
+        self.usage_list.insert(engine.id);
 
         Ok(engine)
     }
 
+    async fn build(&self) -> io::Result<(Engine<B>, usize)> {
+        build(
+            &*self.builder,
+            &self.placement,
+            self.build_attempts,
+            self.build_backoff,
+        )
+        .await
+    }
+
+    fn prepare(&mut self, mut engine: Engine<B>, position: usize, count: usize) -> Engine<B> {
+        // the first engines are recycled earlier
+        // so they're not recreated at once
+        engine.recycle = if self.count_built < self.cap {
+            self.recycle.staggered(position, count)
+        } else {
+            self.recycle
+        };
+        self.count_built += 1;
+
+        engine
+    }
+
     pub fn return_back(&mut self, engine: Engine<B>) {
         self.usage_list.remove(&engine.id);
         self.free_list.push(engine);
     }
 
+    /// Forgets an engine which was recycled so a new one is built on the next `obtain`.
+    pub fn release(&mut self, id: EngineId) {
+        self.usage_list.remove(&id);
+        self.unplace(id);
+    }
+
+    /// Forgets an engine which session is lost.
+    /// It returns `true` if the engine is going to be replaced by a new one on the next `obtain`
+    /// and `false` if the limit of rebuilds is reached so the ring shrinks instead.
+    pub fn discard(&mut self, id: EngineId) -> bool {
+        self.usage_list.remove(&id);
+        self.unplace(id);
+
+        if self.count_rebuilds < self.max_rebuilds {
+            self.count_rebuilds += 1;
+            true
+        } else {
+            self.cap -= 1;
+            false
+        }
+    }
+
+    fn unplace(&mut self, id: EngineId) {
+        if let Some(endpoint) = self.placed.remove(&id) {
+            self.placement.free(endpoint);
+        }
+    }
+
+    /// Returns an amount of engines which failed to be built after all attempts.
+    pub fn count_failed_builds(&self) -> usize {
+        self.count_failed_builds
+    }
+
+    pub fn count_rebuilds(&self) -> usize {
+        self.count_rebuilds
+    }
+
+    /// Returns an amount of engines which are being built in background.
+    pub fn count_building(&self) -> usize {
+        self.count_building
+    }
+
     pub fn count_engines_in_use(&self) -> usize {
         self.usage_list.len()
     }
@@ -62,14 +284,163 @@ where
     }
 }
 
+/// Builds an engine retrying failed attempts with backoff.
+/// It returns the engine and an index of an endpoint it's placed on.
+async fn build<EB>(
+    builder: &EB,
+    placement: &Placement,
+    attempts: usize,
+    backoff: Duration,
+) -> io::Result<(Engine<EB::Backend>, usize)>
+where
+    EB: EngineBuilder,
+{
+    let mut delay = backoff;
+    let mut attempt = 1;
+    loop {
+        match build_on_any(builder, placement).await {
+            Ok(engine) => return Ok(engine),
+            Err(err) if attempt < attempts => {
+                warn!(
+                    "Failed to build an engine on attempt {}, retry in {:?}; {}",
+                    attempt, delay, err
+                );
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+                attempt += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Tries endpoints one by one until one of them creates a session.
+async fn build_on_any<EB>(
+    builder: &EB,
+    placement: &Placement,
+) -> io::Result<(Engine<EB::Backend>, usize)>
+where
+    EB: EngineBuilder,
+{
+    let mut tried = Vec::new();
+    let mut last_err = None;
+    while let Some((i, address)) = placement.reserve(&tried) {
+        match builder.build(&address).await {
+            Ok(engine) => return Ok((engine, i)),
+            Err(err) => {
+                warn!("Endpoint {} failed to create a session; {}", address, err);
+                placement.refuse(i);
+                tried.push(i);
+                last_err = Some(err);
+            }
+        }
+    }
+
+    Err(last_err.unwrap_or_else(|| {
+        io::Error::new(io::ErrorKind::Other, "No endpoint has room for an engine")
+    }))
+}
+
+/// A placement of engines across webdriver endpoints.
+///
+/// It's shared by concurrent builds so it's guarded by a mutex.
+#[derive(Debug)]
+struct Placement {
+    slots: Mutex<Vec<Slot>>,
+}
+
+#[derive(Debug)]
+struct Slot {
+    endpoint: Endpoint,
+    count_engines: usize,
+    refused_at: Option<Instant>,
+}
+
+impl Slot {
+    fn has_room(&self) -> bool {
+        self.endpoint
+            .capacity
+            .map_or(true, |capacity| self.count_engines < capacity)
+    }
+
+    fn is_refusing(&self) -> bool {
+        self.refused_at
+            .map_or(false, |time| time.elapsed() < REFUSAL_COOLDOWN)
+    }
+}
+
+impl Placement {
+    fn new(endpoints: Vec<Endpoint>) -> Self {
+        let slots = endpoints
+            .into_iter()
+            .map(|endpoint| Slot {
+                endpoint,
+                count_engines: 0,
+                refused_at: None,
+            })
+            .collect();
+
+        Self {
+            slots: Mutex::new(slots),
+        }
+    }
+
+    /// Returns a total capacity of endpoints if each of them is limited.
+    fn capacity(&self) -> Option<usize> {
+        self.slots
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|slot| slot.endpoint.capacity)
+            .sum()
+    }
+
+    /// Takes a place on the least loaded endpoint relatively to its weight.
+    /// Endpoints which recently refused a session are picked only if there's no other choice.
+    fn reserve(&self, exclude: &[usize]) -> Option<(usize, Url)> {
+        let mut slots = self.slots.lock().unwrap();
+        let (i, slot) = slots
+            .iter_mut()
+            .enumerate()
+            .filter(|(i, slot)| !exclude.contains(i) && slot.has_room())
+            .min_by(|(_, a), (_, b)| {
+                a.is_refusing().cmp(&b.is_refusing()).then_with(|| {
+                    (a.count_engines * b.endpoint.weight)
+                        .cmp(&(b.count_engines * a.endpoint.weight))
+                })
+            })?;
+
+        slot.count_engines += 1;
+        Some((i, slot.endpoint.address.clone()))
+    }
+
+    /// Frees a place on an endpoint which failed to create a session.
+    fn refuse(&self, i: usize) {
+        let mut slots = self.slots.lock().unwrap();
+        slots[i].count_engines -= 1;
+        slots[i].refused_at = Some(Instant::now());
+    }
+
+    fn free(&self, i: usize) {
+        self.slots.lock().unwrap()[i].count_engines -= 1;
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use std::io;
+    use std::{
+        io,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Mutex,
+        },
+        time::Duration,
+    };
 
     use crate::{
         backend::{Backend, BackendError, SearchResult},
-        engine::Engine,
-        engine_builder::EngineBuilder,
+        engine::{Engine, RecyclePolicy},
+        engine_builder::{Endpoint, EngineBuilder},
         engine_ring::EngineRing,
     };
     use async_trait::async_trait;
@@ -102,6 +473,156 @@ mod tests {
         assert_eq!(id, engine.id);
     }
 
+    #[test]
+    async fn ring_rebuild_engine() {
+        let builder = MockBuilder::new(vec![(); 3]);
+        let mut ring = EngineRing::new(builder, 2);
+        ring.set_max_rebuilds(1);
+
+        let engine_1 = ring.obtain().await.unwrap();
+        let engine_2 = ring.obtain().await.unwrap();
+
+        assert!(ring.discard(engine_1.id));
+        assert_eq!(ring.capacity(), 2);
+        assert!(matches!(ring.obtain().await, Ok(engine) if engine.id == 2));
+
+        assert!(!ring.discard(engine_2.id));
+        assert_eq!(ring.capacity(), 1);
+        assert_eq!(ring.count_engines_in_use(), 1);
+        assert_eq!(ring.count_rebuilds(), 1);
+    }
+
+    #[test]
+    async fn ring_stagger_recycling() {
+        let builder = MockBuilder::new(vec![(); 3]);
+        let mut ring = EngineRing::new(builder, 2);
+        ring.set_recycle_policy(RecyclePolicy {
+            pages: Some(10),
+            age: None,
+        });
+
+        let engine_1 = ring.obtain().await.unwrap();
+        let engine_2 = ring.obtain().await.unwrap();
+        assert_eq!(engine_1.recycle.pages, Some(5));
+        assert_eq!(engine_2.recycle.pages, Some(10));
+
+        ring.release(engine_1.id);
+        let engine_3 = ring.obtain().await.unwrap();
+        assert_eq!(engine_3.recycle.pages, Some(10));
+    }
+
+    #[test]
+    async fn ring_start_with_failures() {
+        let builder = MockBuilder::new(vec![(); 2]).failing(1);
+        let mut ring = EngineRing::new(builder, 3);
+        ring.set_build_retries(1, Duration::default());
+
+        assert_eq!(ring.start().await.unwrap(), 2);
+        assert_eq!(ring.capacity(), 2);
+        assert_eq!(ring.count_failed_builds(), 1);
+        assert!(ring.obtain().await.is_ok());
+        assert!(ring.obtain().await.is_ok());
+    }
+
+    #[test]
+    async fn ring_start_fails_without_engines() {
+        let builder = MockBuilder::new(vec![]).failing(1);
+        let mut ring = EngineRing::new(builder, 1);
+        ring.set_build_retries(1, Duration::default());
+
+        assert!(ring.start().await.is_err());
+    }
+
+    #[test]
+    async fn ring_retries_build() {
+        let builder = MockBuilder::new(vec![()]).failing(2);
+        let mut ring = EngineRing::new(builder, 1);
+        ring.set_build_retries(3, Duration::from_millis(1));
+
+        assert!(ring.obtain().await.is_ok());
+        assert_eq!(ring.count_failed_builds(), 0);
+    }
+
+    #[test]
+    async fn ring_build_in_background() {
+        let builder = MockBuilder::new(vec![()]).failing(1);
+        let mut ring = EngineRing::new(builder, 2);
+        ring.set_build_retries(1, Duration::default());
+
+        let first = ring.build_detached();
+        let second = ring.build_detached();
+        assert_eq!(ring.count_building(), 2);
+
+        let result = tokio::spawn(first).await.unwrap();
+        assert!(ring.finish_build(result).is_err());
+        assert_eq!(ring.capacity(), 1);
+
+        let engine = ring.finish_build(second.await).unwrap();
+        assert_eq!(engine.id, 0);
+        assert_eq!(ring.count_building(), 0);
+        assert_eq!(ring.count_engines_in_use(), 1);
+    }
+
+    #[test]
+    async fn ring_place_engines_by_weight() {
+        let endpoint = |address: &str, weight| Endpoint {
+            address: Url::parse(address).unwrap(),
+            capacity: None,
+            weight,
+        };
+        let builder = MockBuilder::new(vec![(); 3]).with_endpoints(vec![
+            endpoint("http://node1:4444", 1),
+            endpoint("http://node2:4444", 2),
+        ]);
+        let mut ring = EngineRing::new(builder, 3);
+
+        for _ in 0..3 {
+            ring.obtain().await.unwrap();
+        }
+
+        let used = ring.builder.used.lock().unwrap();
+        let count = |address: &str| {
+            used.iter()
+                .filter(|url| *url == &Url::parse(address).unwrap())
+                .count()
+        };
+        assert_eq!(count("http://node1:4444"), 1);
+        assert_eq!(count("http://node2:4444"), 2);
+    }
+
+    #[test]
+    async fn ring_limited_by_endpoints_capacity() {
+        let builder = MockBuilder::new(vec![(); 2]).with_endpoints(vec![Endpoint {
+            address: Url::parse("http://node1:4444").unwrap(),
+            capacity: Some(2),
+            weight: 1,
+        }]);
+        let ring = EngineRing::new(builder, 4);
+
+        assert_eq!(ring.capacity(), 2);
+    }
+
+    #[test]
+    async fn ring_failover_to_another_endpoint() {
+        let builder = MockBuilder::new(vec![(); 2])
+            .with_endpoints(vec![
+                Endpoint::new(Url::parse("http://node1:4444").unwrap()),
+                Endpoint::new(Url::parse("http://node2:4444").unwrap()),
+            ])
+            .refusing("http://node1:4444");
+        let mut ring = EngineRing::new(builder, 2);
+        ring.set_build_retries(1, Duration::default());
+
+        assert_eq!(ring.start().await.unwrap(), 2);
+        assert_eq!(
+            *ring.builder.used.lock().unwrap(),
+            vec![
+                Url::parse("http://node2:4444").unwrap(),
+                Url::parse("http://node2:4444").unwrap()
+            ]
+        );
+    }
+
     #[test]
     #[should_panic]
     async fn panic_on_exceeding_cap() {
@@ -118,13 +639,39 @@ mod tests {
     }
 
     struct MockBuilder {
-        backends: Vec<()>,
-        id: usize,
+        backends: Mutex<Vec<()>>,
+        failures: AtomicUsize,
+        id: AtomicUsize,
+        endpoints: Vec<Endpoint>,
+        refusing: Option<Url>,
+        used: Mutex<Vec<Url>>,
     }
 
     impl MockBuilder {
         fn new(backends: Vec<()>) -> Self {
-            Self { backends, id: 0 }
+            Self {
+                backends: Mutex::new(backends),
+                failures: AtomicUsize::new(0),
+                id: AtomicUsize::new(0),
+                endpoints: vec![Endpoint::new(Url::parse("http://localhost:4444").unwrap())],
+                refusing: None,
+                used: Mutex::new(Vec::new()),
+            }
+        }
+
+        fn with_endpoints(mut self, endpoints: Vec<Endpoint>) -> Self {
+            self.endpoints = endpoints;
+            self
+        }
+
+        fn refusing(mut self, endpoint: &str) -> Self {
+            self.refusing = Some(Url::parse(endpoint).unwrap());
+            self
+        }
+
+        fn failing(self, failures: usize) -> Self {
+            self.failures.store(failures, Ordering::SeqCst);
+            self
         }
     }
 
@@ -132,14 +679,28 @@ mod tests {
     impl EngineBuilder for MockBuilder {
         type Backend = ();
 
-        async fn build(&mut self) -> io::Result<Engine<Self::Backend>> {
-            if self.backends.is_empty() {
+        fn endpoints(&self) -> Vec<Endpoint> {
+            self.endpoints.clone()
+        }
+
+        async fn build(&self, endpoint: &Url) -> io::Result<Engine<Self::Backend>> {
+            if self.refusing.as_ref() == Some(endpoint) {
+                return Err(io::Error::new(io::ErrorKind::Other, "session refused"));
+            }
+
+            if self.failures.load(Ordering::SeqCst) > 0 {
+                self.failures.fetch_sub(1, Ordering::SeqCst);
+                return Err(io::Error::new(io::ErrorKind::Other, "build failed"));
+            }
+
+            let mut backends = self.backends.lock().unwrap();
+            if backends.is_empty() {
                 panic!("Build call wasn't expected");
             }
 
-            let backend = self.backends.remove(0);
-            let id = self.id;
-            self.id += 1;
+            let backend = backends.remove(0);
+            let id = self.id.fetch_add(1, Ordering::SeqCst);
+            self.used.lock().unwrap().push(endpoint.clone());
 
             Ok(Engine::new(id, backend, &[]))
         }
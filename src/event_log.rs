@@ -0,0 +1,215 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! An audit trail of a crawl.
+//!
+//! Each event is written as a JSON object on its own line,
+//! so a history of a page is reconstructed by its url.
+//! Lines are written by a separate thread so a crawl isn't blocked by a disk.
+
+use crate::{
+    backend::BackendError,
+    engine::{EngineId, Page},
+    observer::{CrawlObserver, FilterReason},
+    workload::Statistics,
+};
+use async_trait::async_trait;
+use log::error;
+use serde_json::{json, Map, Value};
+use std::{
+    fs::OpenOptions,
+    io::{self, LineWriter, Write},
+    path::Path,
+    time::Duration,
+};
+use tokio::sync::{mpsc, oneshot};
+use url::Url;
+
+/// An observer which writes crawl events to a file.
+#[derive(Debug)]
+pub struct EventLog {
+    lines: mpsc::UnboundedSender<Message>,
+}
+
+#[derive(Debug)]
+enum Message {
+    Line(String),
+    Flush(oneshot::Sender<io::Result<()>>),
+}
+
+impl EventLog {
+    /// Opens a file to append events to.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self::new(file))
+    }
+
+    /// Starts a thread which writes events to a writer until the log is dropped.
+    pub fn new<W: Write + Send + 'static>(writer: W) -> Self {
+        let (lines, mut receiver) = mpsc::unbounded_channel();
+        std::thread::spawn(move || {
+            let mut writer = LineWriter::new(writer);
+            while let Some(message) = receiver.blocking_recv() {
+                match message {
+                    Message::Line(line) => {
+                        if let Err(err) = writeln!(writer, "{}", line) {
+                            error!("Failed to write an event {}", err);
+                        }
+                    }
+                    Message::Flush(done) => {
+                        let _ = done.send(writer.flush());
+                    }
+                }
+            }
+        });
+
+        Self { lines }
+    }
+
+    fn write(&self, event: &str, url: &Url, fields: Value) {
+        let mut object = Map::new();
+        object.insert("time".to_owned(), json!(chrono::Utc::now().to_rfc3339()));
+        object.insert("event".to_owned(), json!(event));
+        object.insert("url".to_owned(), json!(url.as_str()));
+        if let Value::Object(fields) = fields {
+            object.extend(fields);
+        }
+
+        // the thread is there as long as the log is
+        let _ = self
+            .lines
+            .send(Message::Line(Value::Object(object).to_string()));
+    }
+}
+
+fn millis(duration: Option<Duration>) -> Option<u64> {
+    duration.map(|duration| duration.as_millis() as u64)
+}
+
+#[async_trait]
+impl CrawlObserver for EventLog {
+    async fn on_url_enqueued(&self, url: &Url, source: Option<&Url>) {
+        self.write(
+            "enqueued",
+            url,
+            json!({ "source": source.map(Url::as_str) }),
+        );
+    }
+
+    async fn on_robots_checked(&self, url: &Url, is_allowed: bool) {
+        self.write("robots", url, json!({ "allowed": is_allowed }));
+    }
+
+    async fn on_url_dispatched(&self, url: &Url, engine: EngineId) {
+        self.write("dispatched", url, json!({ "engine": engine }));
+    }
+
+    async fn on_page_result(&self, page: &Page) {
+        let metrics = &page.metrics;
+        self.write(
+            "visited",
+            &page.url,
+            json!({
+                "status": page.status,
+                "load_ms": millis(metrics.load_time),
+                "script_ms": millis(metrics.script_time),
+                "size": metrics.size,
+                "links": metrics.count_links,
+                "filtered": metrics.count_filtered,
+                "collected": page.is_collected(),
+            }),
+        );
+    }
+
+    async fn on_error(&self, url: &Url, err: &BackendError) {
+        self.write(
+            "error",
+            url,
+            json!({ "class": err.class().name(), "message": err.to_string() }),
+        );
+    }
+
+    async fn on_retry_scheduled(&self, url: &Url, attempt: usize, delay: Duration) {
+        self.write(
+            "retry",
+            url,
+            json!({ "attempt": attempt, "delay_ms": delay.as_millis() as u64 }),
+        );
+    }
+
+    async fn on_url_filtered(&self, url: &Url, reason: FilterReason<'_>) {
+        let fields = match reason {
+            FilterReason::Filter(filter) => {
+                json!({ "reason": "filter", "filter": filter.to_string() })
+            }
+            FilterReason::Robots => json!({ "reason": "robots" }),
+            FilterReason::FailedDomain => json!({ "reason": "failed-domain" }),
+        };
+
+        self.write("filtered", url, fields);
+    }
+
+    /// Waits for all events to be written.
+    async fn on_finish(&self, _: &Statistics) {
+        let (done, flushed) = oneshot::channel();
+        let _ = self.lines.send(Message::Flush(done));
+        match flushed.await {
+            Ok(Ok(())) => (),
+            Ok(Err(err)) => error!("Failed to flush an event log {}", err),
+            Err(..) => error!("An event log stopped writing"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filters::Filter;
+    use std::sync::{Arc, Mutex};
+
+    /// A buffer which is inspected while an event log owns it.
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn events() {
+        let buffer = Buffer::default();
+        let log = EventLog::new(buffer.clone());
+        let url = Url::parse("http://example.net/page").unwrap();
+        let source = Url::parse("http://example.net").unwrap();
+        let filter = Filter::Domain(vec!["example.org".to_owned()]);
+
+        log.on_url_enqueued(&url, Some(&source)).await;
+        log.on_url_dispatched(&url, 2).await;
+        log.on_retry_scheduled(&url, 1, Duration::from_secs(1))
+            .await;
+        log.on_url_filtered(&url, FilterReason::Filter(&filter))
+            .await;
+        log.on_finish(&Statistics::default()).await;
+
+        let text = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let events = text
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(events.len(), 4);
+        assert_eq!(events[0]["event"], json!("enqueued"));
+        assert_eq!(events[0]["url"], json!("http://example.net/page"));
+        assert_eq!(events[0]["source"], json!("http://example.net/"));
+        assert_eq!(events[1]["engine"], json!(2));
+        assert_eq!(events[2]["delay_ms"], json!(1000));
+        assert_eq!(events[3]["filter"], json!("domain=example.org"));
+    }
+}
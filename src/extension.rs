@@ -0,0 +1,77 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Vendor specific webdriver commands which are not covered by `thirtyfour`.
+
+use serde_json::{json, Value};
+use thirtyfour::{
+    prelude::WebDriverResult, ExtensionCommand, RequestMethod, WebDriver, WebDriverCommands,
+};
+
+#[derive(Debug)]
+pub(crate) struct Command {
+    method: RequestMethod,
+    endpoint: String,
+    params: Option<Value>,
+}
+
+impl Command {
+    pub(crate) fn get(endpoint: impl Into<String>) -> Self {
+        Self {
+            method: RequestMethod::Get,
+            endpoint: endpoint.into(),
+            params: None,
+        }
+    }
+
+    pub(crate) fn post(endpoint: impl Into<String>, params: Value) -> Self {
+        Self {
+            method: RequestMethod::Post,
+            endpoint: endpoint.into(),
+            params: Some(params),
+        }
+    }
+}
+
+impl ExtensionCommand for Command {
+    fn parameters_json(&self) -> Option<Value> {
+        self.params.clone()
+    }
+
+    fn method(&self) -> RequestMethod {
+        self.method.clone()
+    }
+
+    fn endpoint(&self) -> String {
+        self.endpoint.clone()
+    }
+}
+
+/// Runs a command and returns its `value` field.
+pub(crate) async fn execute(driver: &WebDriver, cmd: Command) -> WebDriverResult<Value> {
+    let mut response = driver.extension_command(cmd).await?;
+    match response.get_mut("value") {
+        Some(value) => Ok(value.take()),
+        None => Ok(response),
+    }
+}
+
+/// Runs a Chrome DevTools Protocol command.
+/// It's supported only by Chromium based drivers which expose it under a vendor prefix,
+/// `goog` for chromedriver and `ms` for msedgedriver.
+pub(crate) async fn cdp(
+    driver: &WebDriver,
+    vendor: &str,
+    cmd: &str,
+    params: Value,
+) -> WebDriverResult<Value> {
+    execute(
+        driver,
+        Command::post(
+            format!("/{}/cdp/execute", vendor),
+            json!({ "cmd": cmd, "params": params }),
+        ),
+    )
+    .await
+}
@@ -3,6 +3,7 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use fancy_regex::Regex;
+use std::fmt::{self, Display};
 use url::Url;
 
 #[derive(Debug, Clone)]
@@ -28,6 +29,15 @@ impl Filter {
     }
 }
 
+impl Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Regex(regex) => write!(f, "regex={}", regex.as_str()),
+            Self::Domain(domains) => write!(f, "domain={}", domains.join(",")),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
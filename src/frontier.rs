@@ -0,0 +1,91 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Urls which wait to be visited.
+//!
+//! A frontier is a queue of urls and a store of urls which were already seen.
+//! A default one keeps both in memory, a custom one might keep them in a database
+//! so a crawl is shared by a few processes or continued after a restart.
+
+use std::collections::{HashSet, VecDeque};
+use url::Url;
+
+pub trait Frontier: Send {
+    /// Remembers a url as seen.
+    /// It returns `false` if the url was seen before so it's not queued again.
+    fn mark_seen(&mut self, url: &Url) -> bool;
+
+    /// Queues a url to be visited before urls which are already queued.
+    fn push(&mut self, url: Url);
+
+    /// Queues a url to be visited after urls which are already queued.
+    fn defer(&mut self, url: Url);
+
+    /// Takes a next url to visit.
+    fn pop(&mut self) -> Option<Url>;
+
+    /// Returns an amount of queued urls.
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryFrontier {
+    queue: VecDeque<Url>,
+    seen: HashSet<Url>,
+}
+
+impl Frontier for MemoryFrontier {
+    fn mark_seen(&mut self, url: &Url) -> bool {
+        self.seen.insert(url.clone())
+    }
+
+    fn push(&mut self, url: Url) {
+        self.queue.push_back(url);
+    }
+
+    fn defer(&mut self, url: Url) {
+        self.queue.push_front(url);
+    }
+
+    fn pop(&mut self) -> Option<Url> {
+        self.queue.pop_back()
+    }
+
+    fn len(&self) -> usize {
+        self.queue.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_frontier() {
+        let url = |path: &str| {
+            Url::parse("http://example.net")
+                .unwrap()
+                .join(path)
+                .unwrap()
+        };
+        let mut frontier = MemoryFrontier::default();
+
+        assert!(frontier.mark_seen(&url("1")));
+        assert!(!frontier.mark_seen(&url("1")));
+
+        frontier.push(url("1"));
+        frontier.push(url("2"));
+        frontier.defer(url("3"));
+        assert_eq!(frontier.len(), 3);
+
+        assert_eq!(frontier.pop(), Some(url("2")));
+        assert_eq!(frontier.pop(), Some(url("1")));
+        assert_eq!(frontier.pop(), Some(url("3")));
+        assert!(frontier.is_empty());
+    }
+}
@@ -0,0 +1,48 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{
+    engine_builder::{wrap_err, WebDriverConfig},
+    proxy,
+};
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue, ACCEPT_LANGUAGE},
+    Client, Proxy,
+};
+use std::io;
+
+/// Builds a client for requests which are made outside of a browser,
+/// such as robots.txt ones, so they're identified the same way as browser ones
+/// and go through the same proxy.
+pub fn client(cfg: &WebDriverConfig) -> io::Result<Client> {
+    let mut headers = HeaderMap::new();
+    if let Some(lang) = cfg.accept_language.as_ref() {
+        let value = HeaderValue::from_str(lang)
+            .map_err(|e| wrap_err("Failed to parse an Accept-Language header", e))?;
+        headers.insert(ACCEPT_LANGUAGE, value);
+    }
+
+    for (name, value) in &cfg.headers {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|e| wrap_err("Failed to parse a header name", e))?;
+        let value = HeaderValue::from_str(value)
+            .map_err(|e| wrap_err("Failed to parse a header value", e))?;
+        // a header might be repeated
+        headers.append(name, value);
+    }
+
+    // environment variables are already taken into account by the proxy pool
+    let proxies = cfg.proxies.clone();
+    Client::builder()
+        .user_agent(cfg.user_agent.as_str())
+        .default_headers(headers)
+        .timeout(cfg.load_timeout)
+        .danger_accept_invalid_certs(cfg.accepts_insecure_certs())
+        .no_proxy()
+        .proxy(Proxy::custom(move |url| {
+            proxies.route(url).and_then(proxy::proxy_url)
+        }))
+        .build()
+        .map_err(|e| wrap_err("Failed to build an http client", e))
+}
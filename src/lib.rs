@@ -2,27 +2,47 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use artifacts::ArtifactsConfig;
 use backend::Backend;
+use crawler::Crawler;
+use engine::RecyclePolicy;
 use engine_builder::{
-    EngineBuilder, SideRunnerEngineBuilder, WebDriverConfig, WebDriverEngineBuilder,
+    EngineBuilder, ReplayEngineBuilder, SideRunnerEngineBuilder, WebDriverConfig,
+    WebDriverEngineBuilder,
 };
-use engine_ring::EngineRing;
+use engine_ring::DEFAULT_BUILD_BACKOFF;
+use event_log::EventLog;
 use filters::Filter;
-use retry::RetryPool;
+use replay::Archive;
 use serde_json::Value;
-use std::{sync::Arc, time::Duration};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 use tokio::sync::Notify;
 use url::Url;
-use workload::{RetryPolicy, Statistics, Workload};
+use warc::{WarcConfig, WarcWriter};
+use workload::{ErrorPolicy, RetryPolicy, Statistics, StopConditions};
 
+pub mod artifacts;
 pub mod backend;
 pub mod cfg;
+pub mod control;
+pub mod crawler;
 pub mod engine;
 pub mod engine_builder;
 pub mod engine_ring;
+pub mod event_log;
+mod extension;
 pub mod filters;
+pub mod frontier;
+pub mod http;
+pub mod metrics;
+pub mod observer;
+pub mod preflight;
+pub mod progress;
+pub mod proxy;
+pub mod replay;
 pub mod retry;
 pub mod robots;
+pub mod warc;
 pub mod workload;
 
 #[derive(Debug)]
@@ -31,13 +51,31 @@ pub struct CrawlConfig {
     pub wb_config: WebDriverConfig,
     pub filters: Vec<Filter>,
     pub count_engines: usize,
-    pub url_limit: Option<usize>,
+    pub max_rebuilds: usize,
+    pub start_attempts: usize,
+    pub recycle: RecyclePolicy,
+    pub stop_conditions: StopConditions,
     pub retry_policy: RetryPolicy,
     pub retry_threshold: Duration,
+    pub retry_max_delay: Duration,
     pub retry_count: usize,
     pub robot_name: String,
     pub use_robots_txt: bool,
+    pub http_client: reqwest::Client,
+    pub artifacts: ArtifactsConfig,
+    pub warc: Option<WarcConfig>,
+    pub replay: Option<Arc<Archive>>,
+    pub error_policy: ErrorPolicy,
+    pub metrics_addr: Option<SocketAddr>,
+    /// An address of an HTTP endpoint which controls a crawl.
+    pub control_addr: Option<SocketAddr>,
+    /// Show a live view of a crawl in a terminal.
+    pub show_progress: bool,
+    /// A log which crawl events are written to.
+    pub event_log: Option<EventLog>,
     pub urls: Vec<Url>,
+    /// Seed urls which are dropped by filters.
+    pub ignored_urls: Vec<Url>,
 }
 
 #[derive(Debug)]
@@ -53,12 +91,24 @@ pub enum CodeType {
 }
 
 pub async fn crawl(config: CrawlConfig, ctrl: Arc<Notify>) -> (Vec<Value>, Statistics) {
+    if let Some(archive) = config.replay.clone() {
+        let builder = ReplayEngineBuilder::new(
+            config.wb_config.clone(),
+            config.code.text.clone(),
+            config.filters.clone(),
+            archive,
+        );
+
+        return _crawl(config, builder, ctrl).await;
+    }
+
     match config.code.code_type {
         CodeType::Js => {
             let builder = WebDriverEngineBuilder::new(
                 config.wb_config.clone(),
                 config.code.text.clone(),
                 config.filters.clone(),
+                config.artifacts.clone(),
             );
 
             _crawl(config, builder, ctrl).await
@@ -68,6 +118,7 @@ pub async fn crawl(config: CrawlConfig, ctrl: Arc<Notify>) -> (Vec<Value>, Stati
                 config.wb_config.clone(),
                 config.code.text.clone(),
                 config.filters.clone(),
+                config.artifacts.clone(),
             );
 
             _crawl(config, builder, ctrl).await
@@ -81,33 +132,105 @@ async fn _crawl<B, Builder>(
     ctrl: Arc<Notify>,
 ) -> (Vec<Value>, Statistics)
 where
-    Builder: EngineBuilder<Backend = B>,
+    Builder: EngineBuilder<Backend = B> + Send + Sync + 'static,
     B: Backend + Send + 'static,
 {
-    let ring = EngineRing::new(builder, config.count_engines);
-    let retry_pool = RetryPool::new(config.retry_threshold, config.retry_count);
-    let workload = Workload::new(
-        ring,
-        config.url_limit,
-        config.retry_policy,
-        retry_pool,
-        config.use_robots_txt,
-        config.robot_name,
-    );
-
-    workload.start(config.urls, ctrl).await
+    let mut crawler = Crawler::builder(builder)
+        .engines(config.count_engines)
+        .max_rebuilds(config.max_rebuilds)
+        .build_retries(config.start_attempts, DEFAULT_BUILD_BACKOFF)
+        .recycle(config.recycle)
+        .retry(
+            config.retry_policy,
+            config.retry_threshold,
+            config.retry_count,
+        )
+        .retry_max_delay(config.retry_max_delay)
+        .error_policy(config.error_policy)
+        .seeds(config.urls)
+        .stop_on(ctrl);
+
+    for filter in config.filters {
+        crawler = crawler.filter(filter);
+    }
+
+    let StopConditions {
+        max_duration,
+        max_visited,
+        max_collected,
+    } = config.stop_conditions;
+    if let Some(limit) = max_collected {
+        crawler = crawler.limit(limit);
+    }
+
+    if let Some(max_visited) = max_visited {
+        crawler = crawler.max_visited(max_visited);
+    }
+
+    if let Some(max_duration) = max_duration {
+        crawler = crawler.max_duration(max_duration);
+    }
+
+    if config.use_robots_txt {
+        crawler = crawler.robots(config.robot_name, config.http_client);
+    }
+
+    if let Some(warc) = config.warc {
+        let writer = WarcWriter::new(warc, config.wb_config.request_headers());
+        crawler = crawler.warc(writer);
+    }
+
+    if let Some(addr) = config.metrics_addr {
+        crawler = crawler.metrics(addr);
+    }
+
+    if let Some(addr) = config.control_addr {
+        crawler = crawler.control(addr);
+    }
+
+    if config.show_progress {
+        crawler = crawler.progress();
+    }
+
+    if let Some(log) = config.event_log {
+        crawler = crawler.observer(log);
+    }
+
+    crawler.run_to_end().await
+}
+
+/// Creates a unique directory for a test which is removed on drop.
+#[cfg(test)]
+pub(crate) fn test_dir() -> tempfile::TempDir {
+    tempfile::Builder::new()
+        .prefix("doonop-")
+        .tempdir()
+        .unwrap()
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{io, sync::Arc, time::Duration};
+    use std::{
+        io,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
+    };
 
     use crate::{
         Code, CodeType, CrawlConfig, _crawl,
-        backend::{Backend, BackendError, SearchResult},
-        engine::Engine,
-        engine_builder::{Browser, EngineBuilder, WebDriverConfig},
-        workload::RetryPolicy,
+        artifacts::ArtifactsConfig,
+        backend::{Backend, BackendError, ErrorClass, SearchResult},
+        crawler::Crawler,
+        engine::{Engine, HttpErrorPolicy, Page, RecyclePolicy},
+        engine_builder::{Browser, Endpoint, EngineBuilder, WebDriverConfig},
+        filters::Filter,
+        frontier::{Frontier, MemoryFrontier},
+        observer::{CrawlObserver, Discovery},
+        proxy::ProxyPool,
+        workload::{ErrorPolicy, RetryPolicy, StopReason},
     };
     use async_trait::async_trait;
     use serde_json::{json, Value};
@@ -130,7 +253,7 @@ mod tests {
 
         let (data, _) = _crawl(config, builder, ctrl).await;
 
-        assert_eq!(data, vec![json!("d1"), json!("d2"), json!(null)])
+        assert_eq!(data, vec![json!("d1"), json!("d2")])
     }
 
     #[test]
@@ -154,38 +277,331 @@ mod tests {
         assert_eq!(data, vec![json!("d1"), json!("d3"), json!("d2")])
     }
 
+    #[test]
+    async fn crawl_with_crawler_handle() {
+        let builder = MockBuilder::new(vec![MockBackend::new(vec![
+            (
+                &["http://example2.com", "http://example1.com/page"],
+                json!("d1"),
+                None,
+            ),
+            (&[], json!("d2"), None),
+        ])]);
+        let (sender, receiver) = async_channel::unbounded();
+
+        let handle = Crawler::builder(builder)
+            .seeds(vec![Url::parse("http://example1.com").unwrap()])
+            .filter(Filter::Domain(vec!["example1.com".to_owned()]))
+            .retry(RetryPolicy::No, Duration::from_secs(1), 0)
+            .sink(sender)
+            .start();
+        let (data, stats) = handle.await;
+
+        assert_eq!(data, vec![json!("d1"), json!("d2")]);
+        assert_eq!(stats.count_visited, 2);
+        assert_eq!(receiver.try_recv().unwrap(), json!("d1"));
+        assert_eq!(receiver.try_recv().unwrap(), json!("d2"));
+    }
+
+    #[test]
+    async fn crawl_with_observer() {
+        struct SkipObserver {
+            skip: Url,
+            pages: Arc<Mutex<Vec<Url>>>,
+        }
+
+        #[async_trait]
+        impl CrawlObserver for SkipObserver {
+            async fn on_url_discovered(&self, url: &Url, _: &Url) -> Discovery {
+                if url == &self.skip {
+                    Discovery::Skip
+                } else {
+                    Discovery::Keep
+                }
+            }
+
+            async fn on_page_result(&self, page: &Page) {
+                self.pages.lock().unwrap().push(page.url.clone());
+            }
+        }
+
+        let builder = MockBuilder::new(vec![MockBackend::new(vec![
+            (
+                &["http://example2.com", "http://example3.com"],
+                json!("d1"),
+                None,
+            ),
+            (&[], json!("d2"), None),
+        ])]);
+        let pages = Arc::new(Mutex::new(Vec::new()));
+
+        let (data, _) = Crawler::builder(builder)
+            .seeds(vec![Url::parse("http://example1.com").unwrap()])
+            .retry(RetryPolicy::No, Duration::from_secs(1), 0)
+            .observer(SkipObserver {
+                skip: Url::parse("http://example3.com").unwrap(),
+                pages: pages.clone(),
+            })
+            .run_to_end()
+            .await;
+
+        assert_eq!(data, vec![json!("d1"), json!("d2")]);
+        assert_eq!(
+            *pages.lock().unwrap(),
+            vec![
+                Url::parse("http://example1.com").unwrap(),
+                Url::parse("http://example2.com").unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    async fn crawl_with_unavailable_page() {
+        let mut backend = MockBackend::new(vec![
+            (&["http://example2.com"], json!("d1"), None),
+            (&[], json!("d2"), None),
+        ]);
+        backend.results[1].0.status = Some(503);
+        let builder = MockBuilder::new(vec![backend]);
+
+        // pages are extracted by default but 503 is still an error
+        let (data, stats) = Crawler::builder(builder)
+            .seeds(vec![Url::parse("http://example1.com").unwrap()])
+            .retry(RetryPolicy::No, Duration::from_secs(1), 0)
+            .run_to_end()
+            .await;
+
+        assert_eq!(data, vec![json!("d1")]);
+        assert_eq!(stats.count_errors, 1);
+        assert_eq!(stats.errors.get(&ErrorClass::ServerError), Some(&1));
+    }
+
+    #[test]
+    async fn crawl_with_frontier() {
+        let builder = MockBuilder::new(vec![MockBackend::new(vec![
+            (
+                &["http://example2.com", "http://example3.com"],
+                json!("d1"),
+                None,
+            ),
+            (&[], json!("d2"), None),
+        ])]);
+        // a url seen by a previous crawl isn't visited again
+        let mut frontier = MemoryFrontier::default();
+        frontier.mark_seen(&Url::parse("http://example3.com").unwrap());
+
+        let (data, stats) = Crawler::builder(builder)
+            .seeds(vec![Url::parse("http://example1.com").unwrap()])
+            .retry(RetryPolicy::No, Duration::from_secs(1), 0)
+            .frontier(frontier)
+            .run_to_end()
+            .await;
+
+        assert_eq!(data, vec![json!("d1"), json!("d2")]);
+        assert_eq!(stats.count_visited, 2);
+    }
+
+    #[test]
+    async fn crawl_continued_from_frontier() {
+        let builder = MockBuilder::new(vec![MockBackend::new(vec![
+            (&["http://example1.com"], json!("d1"), None),
+            (&[], json!("d2"), None),
+        ])]);
+        // a frontier of a stopped crawl
+        let mut frontier = MemoryFrontier::default();
+        for url in &["http://example1.com", "http://example2.com"] {
+            let url = Url::parse(url).unwrap();
+            frontier.mark_seen(&url);
+            frontier.push(url);
+        }
+
+        let (data, stats) = Crawler::builder(builder)
+            .retry(RetryPolicy::No, Duration::from_secs(1), 0)
+            .frontier(frontier)
+            .run_to_end()
+            .await;
+
+        assert_eq!(data, vec![json!("d1"), json!("d2")]);
+        assert_eq!(stats.count_visited, 2);
+    }
+
+    #[test]
+    async fn crawl_paused_with_added_seeds() {
+        let builder = MockBuilder::new(vec![MockBackend::new(vec![
+            (&[], json!("d1"), Some(Duration::from_millis(200))),
+            (&[], json!("d2"), None),
+        ])]);
+
+        let handle = Crawler::builder(builder)
+            .seeds(vec![Url::parse("http://example1.com").unwrap()])
+            .retry(RetryPolicy::No, Duration::from_secs(1), 0)
+            .start();
+        handle.pause();
+        handle.add_seeds(vec![Url::parse("http://example2.com").unwrap()]);
+
+        // the first page is finished while a crawl is paused
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let stats = handle.statistics();
+        assert!(stats.is_paused);
+        assert_eq!(stats.count_visited, 1);
+        assert_eq!(stats.frontier_size, 1);
+
+        handle.resume();
+        let (data, stats) = handle.await;
+
+        assert_eq!(data, vec![json!("d1"), json!("d2")]);
+        assert!(!stats.is_paused);
+    }
+
+    #[test]
+    async fn crawl_with_limit() {
+        let config = default_config(vec![Url::parse("http://example1.com").unwrap()], 1, Some(2));
+        let ctrl = Arc::new(Notify::new());
+        let builder = MockBuilder::new(vec![MockBackend::new(vec![
+            (
+                &["http://example2.com", "http://example3.com"],
+                json!("d1"),
+                None,
+            ),
+            (&[], json!(null), None),
+            (&["http://example4.com"], json!("d2"), None),
+        ])]);
+
+        let (data, stats) = _crawl(config, builder, ctrl).await;
+
+        assert_eq!(data, vec![json!("d1"), json!("d2")]);
+        assert_eq!(stats.count_collected, 2);
+        assert_eq!(stats.stop_reason, Some(StopReason::MaxCollected));
+    }
+
+    #[test]
+    async fn crawl_truncated_to_limit() {
+        let builder = MockBuilder::new(vec![
+            MockBackend::new(vec![
+                (
+                    &["http://example2.com", "http://example3.com"],
+                    json!("d1"),
+                    None,
+                ),
+                (&[], json!("d2"), Some(Duration::from_millis(300))),
+            ]),
+            MockBackend::new(vec![(&[], json!("d3"), None)]),
+        ]);
+
+        let (data, stats) = Crawler::builder(builder)
+            .engines(2)
+            .seeds(vec![Url::parse("http://example1.com").unwrap()])
+            .retry(RetryPolicy::No, Duration::from_secs(1), 0)
+            .limit(2)
+            .run_to_end()
+            .await;
+
+        // a page which was being worked on when the limit was reached is dropped
+        assert_eq!(data, vec![json!("d1"), json!("d3")]);
+        assert_eq!(stats.count_visited, 3);
+        assert_eq!(stats.count_collected, 2);
+    }
+
+    #[test]
+    async fn crawl_with_max_visited() {
+        let builder = MockBuilder::new(vec![MockBackend::new(vec![
+            (
+                &["http://example2.com", "http://example3.com"],
+                json!("d1"),
+                None,
+            ),
+            (&[], json!(null), None),
+        ])]);
+
+        let (data, stats) = Crawler::builder(builder)
+            .seeds(vec![Url::parse("http://example1.com").unwrap()])
+            .retry(RetryPolicy::No, Duration::from_secs(1), 0)
+            .max_visited(2)
+            .run_to_end()
+            .await;
+
+        assert_eq!(data, vec![json!("d1")]);
+        assert_eq!(stats.count_collected, 1);
+        assert_eq!(stats.stop_reason, Some(StopReason::MaxVisited));
+    }
+
+    #[test]
+    async fn crawl_with_max_duration() {
+        let builder = MockBuilder::new(vec![MockBackend::new(vec![(
+            &["http://example2.com"],
+            json!("d1"),
+            Some(Duration::from_millis(300)),
+        )])]);
+
+        let (data, stats) = Crawler::builder(builder)
+            .seeds(vec![Url::parse("http://example1.com").unwrap()])
+            .retry(RetryPolicy::No, Duration::from_secs(1), 0)
+            .max_duration(Duration::from_millis(100))
+            .run_to_end()
+            .await;
+
+        assert_eq!(data, vec![json!("d1")]);
+        assert_eq!(stats.stop_reason, Some(StopReason::MaxDuration));
+    }
+
     fn default_config(urls: Vec<Url>, count_engines: usize, limit: Option<usize>) -> CrawlConfig {
         CrawlConfig {
             wb_config: WebDriverConfig {
                 load_timeout: Duration::from_secs(1),
                 browser: Browser::Firefox,
-                webdriver_address: Url::parse("http://localhost:4444").unwrap(),
-                proxy: None,
+                endpoints: vec![Endpoint::new(Url::parse("http://localhost:4444").unwrap())],
+                proxies: Arc::new(ProxyPool::default()),
+                user_agent: "DonoopRobot".to_string(),
+                accept_language: None,
+                headers: Vec::new(),
+                http_errors: HttpErrorPolicy::Extract,
+                headless: true,
+                capabilities: None,
             },
             robot_name: "DonoopRobot".to_string(),
             use_robots_txt: false,
+            http_client: reqwest::Client::new(),
+            artifacts: ArtifactsConfig::default(),
+            warc: None,
+            replay: None,
+            error_policy: ErrorPolicy::default(),
+            metrics_addr: None,
+            control_addr: None,
+            show_progress: false,
+            event_log: None,
             retry_policy: RetryPolicy::No,
             retry_count: 0,
             retry_threshold: Duration::from_secs(1),
+            retry_max_delay: Duration::from_secs(1),
             code: Code {
                 text: String::new(),
                 code_type: CodeType::Js,
             },
             filters: Vec::new(),
-            url_limit: limit,
+            stop_conditions: StopConditions {
+                max_collected: limit,
+                ..StopConditions::default()
+            },
             urls,
+            ignored_urls: Vec::new(),
             count_engines,
+            max_rebuilds: 0,
+            start_attempts: 1,
+            recycle: RecyclePolicy::default(),
         }
     }
 
     struct MockBuilder {
-        backends: Vec<MockBackend>,
-        id: usize,
+        backends: Mutex<Vec<MockBackend>>,
+        id: AtomicUsize,
     }
 
     impl MockBuilder {
         fn new(backends: Vec<MockBackend>) -> Self {
-            Self { backends, id: 0 }
+            Self {
+                backends: Mutex::new(backends),
+                id: AtomicUsize::new(0),
+            }
         }
     }
 
@@ -193,14 +609,18 @@ mod tests {
     impl EngineBuilder for MockBuilder {
         type Backend = MockBackend;
 
-        async fn build(&mut self) -> io::Result<Engine<Self::Backend>> {
-            if self.backends.is_empty() {
+        fn endpoints(&self) -> Vec<Endpoint> {
+            vec![Endpoint::new(Url::parse("http://localhost:4444").unwrap())]
+        }
+
+        async fn build(&self, _: &Url) -> io::Result<Engine<Self::Backend>> {
+            let mut backends = self.backends.lock().unwrap();
+            if backends.is_empty() {
                 panic!("Build call wasn't expected");
             }
 
-            let backend = self.backends.remove(0);
-            let id = self.id;
-            self.id += 1;
+            let backend = backends.remove(0);
+            let id = self.id.fetch_add(1, Ordering::SeqCst);
 
             Ok(Engine::new(id, backend, &[]))
         }
@@ -2,24 +2,72 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use clap::Clap;
 use doonop::cfg::parse_cfg;
-use doonop::{cfg::Cfg, crawl};
-use log::info;
-use std::sync::Arc;
+use doonop::{
+    cfg::Cfg,
+    crawl,
+    preflight::{preflight, summary},
+};
+use log::{error, info, warn, LevelFilter, Log, Metadata, Record};
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    sync::{Arc, Mutex},
+};
 use tokio::sync::Notify;
 
+const DEFAULT_LOG_FILE: &str = "doonop.log";
+
 #[tokio::main]
 async fn main() {
-    turn_on_loggin();
+    let cfg = match Cfg::load() {
+        Ok(cfg) => cfg,
+        Err(err) if is_dry_run_requested() => {
+            println!("FAIL config: {}", err);
+            std::process::exit(1);
+        }
+        Err(err) => panic!("Error occured while reading options {}", err),
+    };
 
-    info!("Reading config");
+    // logs would break a live view
+    let log_file = match &cfg.log_file {
+        Some(path) => Some(path.as_str()),
+        None if cfg.is_progress_shown() => Some(DEFAULT_LOG_FILE),
+        None => None,
+    };
+    match log_file {
+        Some(path) => turn_on_file_loggin(path),
+        None => turn_on_loggin(),
+    }
 
-    let cfg: Cfg = Cfg::parse();
-    let crawl_config = parse_cfg(cfg).expect("Error occured while dealing with configuration file");
+    let dry_run = cfg.dry_run;
+    let stats_file = cfg.stats_file.clone();
+    let crawl_config = match parse_cfg(cfg) {
+        Ok(config) => config,
+        Err(err) if dry_run => {
+            println!("FAIL config: {}", err);
+            std::process::exit(1);
+        }
+        Err(err) => panic!(
+            "Error occured while dealing with configuration file {}",
+            err
+        ),
+    };
 
     info!("Config sucessfully read");
 
+    if dry_run {
+        print!("{}", summary(&crawl_config));
+
+        let report = preflight(&crawl_config).await;
+        print!("{}", report);
+        if !report.is_ok() {
+            std::process::exit(1);
+        }
+
+        return;
+    }
+
     let ctrl = Arc::new(Notify::new());
     spawn_ctrlc_handler(ctrl.clone());
 
@@ -28,11 +76,53 @@ async fn main() {
     let (data, stats) = crawl(crawl_config, ctrl).await;
 
     info!("Praparing data for printing");
+    for failure in &stats.failures {
+        let attempts = failure
+            .attempts
+            .iter()
+            .map(|a| match a.delay {
+                Some(delay) => format!("{} (retried in {:?})", a.reason, delay),
+                None => a.reason.clone(),
+            })
+            .collect::<Vec<_>>();
+        warn!(
+            "Gave up on {} after {} attempts: {}",
+            failure.url,
+            attempts.len(),
+            attempts.join("; ")
+        );
+    }
+    for (proxy, health) in &stats.proxies {
+        info!(
+            "Proxy {}: visited {}, errors {}, blocks {}, rotations {}",
+            proxy,
+            health.count_visited,
+            health.count_errors,
+            health.count_blocks,
+            health.count_rotations
+        );
+    }
+    if stats.count_failed_engines > 0 {
+        warn!("{} engines failed to start", stats.count_failed_engines);
+    }
+    if stats.count_rebuilds > 0 {
+        warn!("Engines were rebuilt {} times", stats.count_rebuilds);
+    }
+    if let Some(reason) = stats.stop_reason {
+        info!("The crawl was stopped: {}", reason.name());
+    }
     info!(
         "Statistics: visited {}, collected {}, errors {}, retries {}",
         stats.count_visited, stats.count_collected, stats.count_errors, stats.count_retries
     );
 
+    if let Some(path) = stats_file {
+        let json = serde_json::to_string_pretty(&stats.to_json()).unwrap();
+        if let Err(err) = std::fs::write(&path, json) {
+            error!("Failed to write statistics to {}: {}", path, err);
+        }
+    }
+
     for ext in data {
         println!("{}", ext);
     }
@@ -47,6 +137,63 @@ fn spawn_ctrlc_handler(ch: Arc<Notify>) -> tokio::task::JoinHandle<()> {
     })
 }
 
+/// Checks whether a dry run is requested when options couldn't be read,
+/// so only the command line and the environment are looked at.
+fn is_dry_run_requested() -> bool {
+    std::env::args_os().any(|arg| arg == "--dry-run")
+        || std::env::var("DOONOP_DRY_RUN").map_or(false, |value| value == "true")
+}
+
+fn turn_on_file_loggin(path: &str) {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .expect("Error occured while opening a log file");
+    let level = std::env::var("RUST_LOG")
+        .ok()
+        .and_then(|level| level.parse().ok())
+        .unwrap_or(LevelFilter::Info);
+
+    log::set_boxed_logger(Box::new(FileLogger {
+        file: Mutex::new(file),
+        level,
+    }))
+    .unwrap();
+    log::set_max_level(level);
+}
+
+struct FileLogger {
+    file: Mutex<File>,
+    level: LevelFilter,
+}
+
+impl Log for FileLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let mut file = self.file.lock().unwrap();
+        let _ = writeln!(
+            file,
+            "{} {:<5} {} > {}",
+            chrono::Local::now().to_rfc3339(),
+            record.level(),
+            record.target(),
+            record.args()
+        );
+    }
+
+    fn flush(&self) {
+        let _ = self.file.lock().unwrap().flush();
+    }
+}
+
 fn turn_on_loggin() {
     option_env!("RUST_LOG")
         .and_then(|_| {
@@ -0,0 +1,248 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Prometheus metrics of a running crawl.
+//!
+//! Metrics are rendered in the text exposition format from live statistics
+//! on each scrape of any path.
+
+use crate::workload::{Histogram, Statistics};
+use hyper::{
+    header::CONTENT_TYPE,
+    service::{make_service_fn, service_fn},
+    Body, Response, Server,
+};
+use log::error;
+use std::{convert::Infallible, fmt::Write, io, net::SocketAddr, sync::Arc};
+use tokio::sync::{watch, Notify};
+
+/// Starts serving metrics in a background task until a shutdown is notified.
+/// It returns an address the server is bound to.
+pub fn serve(
+    addr: SocketAddr,
+    stats: watch::Receiver<Statistics>,
+    shutdown: Arc<Notify>,
+) -> io::Result<SocketAddr> {
+    let make_service = make_service_fn(move |_| {
+        let stats = stats.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |_| {
+                let body = render(&stats.borrow());
+                async move {
+                    Ok::<_, Infallible>(
+                        Response::builder()
+                            .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+                            .body(Body::from(body))
+                            .unwrap(),
+                    )
+                }
+            }))
+        }
+    });
+
+    let server = Server::try_bind(&addr)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+        .serve(make_service);
+    let addr = server.local_addr();
+    let server = server.with_graceful_shutdown(async move { shutdown.notified().await });
+
+    tokio::spawn(async move {
+        if let Err(err) = server.await {
+            error!("Metrics server failed {}", err);
+        }
+    });
+
+    Ok(addr)
+}
+
+pub fn render(stats: &Statistics) -> String {
+    let mut metrics = Metrics::default();
+
+    metrics.header(
+        "doonop_pages_visited_total",
+        "counter",
+        "Pages which were visited.",
+    );
+    metrics.value("doonop_pages_visited_total", &[], stats.count_visited);
+    metrics.header(
+        "doonop_pages_collected_total",
+        "counter",
+        "Pages which data was collected.",
+    );
+    metrics.value("doonop_pages_collected_total", &[], stats.count_collected);
+    metrics.header(
+        "doonop_retries_total",
+        "counter",
+        "Urls which were scheduled to be retried.",
+    );
+    metrics.value("doonop_retries_total", &[], stats.count_retries);
+    metrics.header("doonop_errors_total", "counter", "Errors by their classes.");
+    let mut errors = stats.errors.iter().collect::<Vec<_>>();
+    errors.sort();
+    for (class, count) in errors {
+        metrics.value("doonop_errors_total", &[("class", class.name())], count);
+    }
+    metrics.header(
+        "doonop_pages_per_second",
+        "gauge",
+        "An average rate of visited pages.",
+    );
+    let elapsed = stats.elapsed.as_secs_f64();
+    let rate = if elapsed > 0.0 {
+        stats.count_visited as f64 / elapsed
+    } else {
+        0.0
+    };
+    metrics.value("doonop_pages_per_second", &[], rate);
+    metrics.header(
+        "doonop_frontier_size",
+        "gauge",
+        "Urls waiting to be visited.",
+    );
+    metrics.value("doonop_frontier_size", &[], stats.frontier_size);
+    metrics.header(
+        "doonop_retry_pool_size",
+        "gauge",
+        "Urls waiting to be retried.",
+    );
+    metrics.value("doonop_retry_pool_size", &[], stats.retry_pool_size);
+    metrics.header("doonop_engines", "gauge", "Engines by their state.");
+    let idle = stats.count_engines.saturating_sub(stats.count_busy_engines);
+    metrics.value(
+        "doonop_engines",
+        &[("state", "busy")],
+        stats.count_busy_engines,
+    );
+    metrics.value("doonop_engines", &[("state", "idle")], idle);
+
+    let mut hosts = stats.hosts.iter().collect::<Vec<_>>();
+    hosts.sort_by(|a, b| a.0.cmp(b.0));
+    for (name, kind, help) in &[
+        (
+            "doonop_host_pages_visited_total",
+            "visited",
+            "Pages which were visited by hosts.",
+        ),
+        (
+            "doonop_host_pages_collected_total",
+            "collected",
+            "Pages which data was collected by hosts.",
+        ),
+        ("doonop_host_errors_total", "errors", "Errors by hosts."),
+    ] {
+        metrics.header(name, "counter", help);
+        for (host, counters) in &hosts {
+            let count = match *kind {
+                "visited" => counters.count_visited,
+                "collected" => counters.count_collected,
+                _ => counters.count_errors,
+            };
+            metrics.value(name, &[("host", host.as_str())], count);
+        }
+    }
+
+    metrics.histogram(
+        "doonop_load_time_seconds",
+        "A time pages took to load.",
+        &stats.load_time,
+    );
+    metrics.histogram(
+        "doonop_script_time_seconds",
+        "A time check scripts took to run.",
+        &stats.script_time,
+    );
+
+    metrics.text
+}
+
+#[derive(Default)]
+struct Metrics {
+    text: String,
+}
+
+impl Metrics {
+    fn header(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.text, "# HELP {} {}", name, help);
+        let _ = writeln!(self.text, "# TYPE {} {}", name, kind);
+    }
+
+    fn value(&mut self, name: &str, labels: &[(&str, &str)], value: impl std::fmt::Display) {
+        let labels = labels
+            .iter()
+            .map(|(label, value)| format!("{}=\"{}\"", label, escape(value)))
+            .collect::<Vec<_>>();
+        if labels.is_empty() {
+            let _ = writeln!(self.text, "{} {}", name, value);
+        } else {
+            let _ = writeln!(self.text, "{}{{{}}} {}", name, labels.join(","), value);
+        }
+    }
+
+    /// Writes a histogram as a summary as its buckets are an approximation.
+    fn histogram(&mut self, name: &str, help: &str, histogram: &Histogram) {
+        self.header(name, "summary", help);
+        for quantile in &["0.5", "0.95", "0.99"] {
+            if let Some(value) = histogram.percentile(quantile.parse().unwrap()) {
+                self.value(name, &[("quantile", quantile)], value.as_secs_f64());
+            }
+        }
+        self.value(&format!("{}_count", name), &[], histogram.count());
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{backend::ErrorClass, workload::HostStatistics};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn scrape() {
+        let mut stats = Statistics {
+            count_visited: 10,
+            elapsed: Duration::from_secs(5),
+            count_engines: 3,
+            count_busy_engines: 1,
+            ..Statistics::default()
+        };
+        stats.errors.insert(ErrorClass::Timeout, 2);
+        stats.hosts.insert(
+            "example.net".to_owned(),
+            HostStatistics {
+                count_visited: 10,
+                ..HostStatistics::default()
+            },
+        );
+        let (_sender, receiver) = watch::channel(stats);
+        let shutdown = Arc::new(Notify::new());
+
+        let addr = serve("127.0.0.1:0".parse().unwrap(), receiver, shutdown.clone()).unwrap();
+        let body = reqwest::get(format!("http://{}/metrics", addr))
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        shutdown.notify_one();
+
+        assert!(body.contains("doonop_pages_visited_total 10\n"));
+        assert!(body.contains("doonop_pages_per_second 2\n"));
+        assert!(body.contains("doonop_errors_total{class=\"timeout\"} 2\n"));
+        assert!(body.contains("doonop_engines{state=\"idle\"} 2\n"));
+        assert!(body.contains("doonop_host_pages_visited_total{host=\"example.net\"} 10\n"));
+        assert!(body.contains("# TYPE doonop_load_time_seconds summary\n"));
+    }
+
+    #[test]
+    fn escape_label() {
+        assert_eq!(escape("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
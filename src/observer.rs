@@ -0,0 +1,207 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Hooks into a crawl for library users.
+//!
+//! Observers are called from the workload and from engines,
+//! so they're shared between tasks.
+
+use crate::{
+    backend::BackendError,
+    engine::{EngineId, Page},
+    filters::Filter,
+    workload::Statistics,
+};
+use async_trait::async_trait;
+use std::{sync::Arc, time::Duration};
+use url::Url;
+
+/// A decision about a discovered url.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Discovery {
+    Keep,
+    /// Don't visit the url.
+    Skip,
+    /// Visit the url before urls which are already queued.
+    Prioritize,
+    /// Visit the url after urls which are already queued.
+    Defer,
+}
+
+/// A reason a url isn't visited.
+#[derive(Debug, Clone, Copy)]
+pub enum FilterReason<'a> {
+    /// The url is ignored by the filter.
+    Filter(&'a Filter),
+    Robots,
+    /// The url's domain is considered to be failing by the error policy.
+    FailedDomain,
+}
+
+/// Callbacks on crawl events.
+/// All of them do nothing by default.
+#[async_trait]
+pub trait CrawlObserver: Send + Sync {
+    /// It's called for each new url found on a page, which passed filters.
+    async fn on_url_discovered(&self, _url: &Url, _page: &Url) -> Discovery {
+        Discovery::Keep
+    }
+
+    /// It's called for each url which is queued to be visited.
+    /// Seeds have no source page.
+    async fn on_url_enqueued(&self, _url: &Url, _source: Option<&Url>) {}
+
+    /// It's called for each url checked against robots.txt before it's visited.
+    /// A url which robots.txt failed to be fetched for is considered disallowed.
+    async fn on_robots_checked(&self, _url: &Url, _is_allowed: bool) {}
+
+    /// It's called when an engine takes a url to work on.
+    async fn on_url_dispatched(&self, _url: &Url, _engine: EngineId) {}
+
+    async fn on_page_result(&self, _page: &Page) {}
+
+    async fn on_error(&self, _url: &Url, _err: &BackendError) {}
+
+    /// It's called when a failed url is scheduled to be visited again,
+    /// `attempt` is a number of the failed attempt.
+    async fn on_retry_scheduled(&self, _url: &Url, _attempt: usize, _delay: Duration) {}
+
+    async fn on_url_filtered(&self, _url: &Url, _reason: FilterReason<'_>) {}
+
+    async fn on_finish(&self, _stats: &Statistics) {}
+}
+
+/// A set of observers which are called in order.
+#[derive(Clone, Default)]
+pub struct Observers {
+    observers: Vec<Arc<dyn CrawlObserver>>,
+}
+
+impl Observers {
+    pub fn push(&mut self, observer: Arc<dyn CrawlObserver>) {
+        self.observers.push(observer);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.observers.is_empty()
+    }
+}
+
+impl std::fmt::Debug for Observers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Observers")
+            .field("count", &self.observers.len())
+            .finish()
+    }
+}
+
+#[async_trait]
+impl CrawlObserver for Observers {
+    /// A skip by any observer wins,
+    /// otherwise a first decision other than keep is taken.
+    async fn on_url_discovered(&self, url: &Url, page: &Url) -> Discovery {
+        let mut discovery = Discovery::Keep;
+        for observer in &self.observers {
+            match observer.on_url_discovered(url, page).await {
+                Discovery::Skip => return Discovery::Skip,
+                Discovery::Keep => (),
+                decision if discovery == Discovery::Keep => discovery = decision,
+                _ => (),
+            }
+        }
+
+        discovery
+    }
+
+    async fn on_url_enqueued(&self, url: &Url, source: Option<&Url>) {
+        for observer in &self.observers {
+            observer.on_url_enqueued(url, source).await;
+        }
+    }
+
+    async fn on_robots_checked(&self, url: &Url, is_allowed: bool) {
+        for observer in &self.observers {
+            observer.on_robots_checked(url, is_allowed).await;
+        }
+    }
+
+    async fn on_url_dispatched(&self, url: &Url, engine: EngineId) {
+        for observer in &self.observers {
+            observer.on_url_dispatched(url, engine).await;
+        }
+    }
+
+    async fn on_page_result(&self, page: &Page) {
+        for observer in &self.observers {
+            observer.on_page_result(page).await;
+        }
+    }
+
+    async fn on_error(&self, url: &Url, err: &BackendError) {
+        for observer in &self.observers {
+            observer.on_error(url, err).await;
+        }
+    }
+
+    async fn on_retry_scheduled(&self, url: &Url, attempt: usize, delay: Duration) {
+        for observer in &self.observers {
+            observer.on_retry_scheduled(url, attempt, delay).await;
+        }
+    }
+
+    async fn on_url_filtered(&self, url: &Url, reason: FilterReason<'_>) {
+        for observer in &self.observers {
+            observer.on_url_filtered(url, reason).await;
+        }
+    }
+
+    async fn on_finish(&self, stats: &Statistics) {
+        for observer in &self.observers {
+            observer.on_finish(stats).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Decide(Discovery);
+
+    #[async_trait]
+    impl CrawlObserver for Decide {
+        async fn on_url_discovered(&self, _: &Url, _: &Url) -> Discovery {
+            self.0
+        }
+    }
+
+    #[tokio::test]
+    async fn composed_discovery() {
+        let url = Url::parse("http://example.net").unwrap();
+        let observers = |decisions: &[Discovery]| {
+            let mut observers = Observers::default();
+            for decision in decisions {
+                observers.push(Arc::new(Decide(*decision)));
+            }
+
+            observers
+        };
+
+        let decide = |decisions: &[Discovery]| {
+            let observers = observers(decisions);
+            let url = url.clone();
+            async move { observers.on_url_discovered(&url, &url).await }
+        };
+
+        assert_eq!(decide(&[]).await, Discovery::Keep);
+        assert_eq!(
+            decide(&[Discovery::Keep, Discovery::Defer, Discovery::Prioritize]).await,
+            Discovery::Defer
+        );
+        assert_eq!(
+            decide(&[Discovery::Prioritize, Discovery::Skip]).await,
+            Discovery::Skip
+        );
+    }
+}
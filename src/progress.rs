@@ -0,0 +1,186 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A live view of a crawl in a terminal.
+//!
+//! It's redrawn in place on stderr from live statistics,
+//! so logs are supposed to be written somewhere else while it's shown.
+
+use crate::workload::Statistics;
+use std::{
+    io::{self, Write},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::{watch, Notify},
+    task::JoinHandle,
+};
+
+const REDRAW_INTERVAL: Duration = Duration::from_millis(250);
+const MAX_URL_WIDTH: usize = 56;
+
+/// Starts redrawing a view in a background task until a shutdown is notified.
+/// The last frame is left on a screen.
+pub fn show(
+    stats: watch::Receiver<Statistics>,
+    limit: Option<usize>,
+    shutdown: Arc<Notify>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(REDRAW_INTERVAL);
+        let mut count_lines = 0;
+        loop {
+            let is_done = tokio::select! {
+                _ = interval.tick() => false,
+                _ = shutdown.notified() => true,
+            };
+
+            let lines = render(&stats.borrow(), limit, Instant::now());
+            // a failed redraw is skipped, the next one might succeed
+            let _ = redraw(&lines, count_lines);
+            count_lines = lines.len();
+
+            if is_done {
+                break;
+            }
+        }
+    })
+}
+
+/// Draws lines over the previously drawn ones.
+fn redraw(lines: &[String], count_previous: usize) -> io::Result<()> {
+    let stderr = io::stderr();
+    let mut stderr = stderr.lock();
+    if count_previous > 0 {
+        write!(stderr, "\x1b[{}A", count_previous)?;
+    }
+
+    for line in lines {
+        writeln!(stderr, "\x1b[2K{}", line)?;
+    }
+
+    // lines of an engine which finished are cleared
+    write!(stderr, "\x1b[J")?;
+    stderr.flush()
+}
+
+pub fn render(stats: &Statistics, limit: Option<usize>, now: Instant) -> Vec<String> {
+    let elapsed = stats.elapsed.as_secs_f64();
+    let rate = |count: usize| {
+        if elapsed > 0.0 {
+            count as f64 / elapsed
+        } else {
+            0.0
+        }
+    };
+
+    let collected = match limit {
+        Some(limit) => format!("{}/{}", stats.count_collected, limit),
+        None => stats.count_collected.to_string(),
+    };
+    let collect_rate = rate(stats.count_collected);
+    let eta = match limit {
+        Some(limit) if collect_rate > 0.0 => {
+            let left = limit.saturating_sub(stats.count_collected) as f64;
+            format_duration(Duration::from_secs_f64(left / collect_rate))
+        }
+        _ => "-".to_owned(),
+    };
+
+    let mut lines = vec![
+        format!(
+            "visited {}  collected {}  errors {}  retries {}",
+            stats.count_visited, collected, stats.count_errors, stats.count_retries
+        ),
+        format!(
+            "frontier {}  retry pool {}  engines {}/{}",
+            stats.frontier_size,
+            stats.retry_pool_size,
+            stats.count_busy_engines,
+            stats.count_engines
+        ),
+        format!(
+            "{:.1} pages/s  {:.1} collected/s  elapsed {}  eta {}",
+            rate(stats.count_visited),
+            collect_rate,
+            format_duration(stats.elapsed),
+            eta
+        ),
+    ];
+
+    for engine in &stats.engines {
+        lines.push(format!(
+            "engine {:<3} {}  {}",
+            engine.id,
+            format_duration(now.saturating_duration_since(engine.started)),
+            truncate(engine.url.as_str(), MAX_URL_WIDTH)
+        ));
+    }
+
+    lines
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+fn truncate(s: &str, width: usize) -> String {
+    if s.chars().count() <= width {
+        return s.to_owned();
+    }
+
+    let mut s = s.chars().take(width - 3).collect::<String>();
+    s.push_str("...");
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::workload::EngineActivity;
+    use url::Url;
+
+    #[test]
+    fn frame() {
+        let now = Instant::now();
+        let stats = Statistics {
+            count_visited: 20,
+            count_collected: 10,
+            elapsed: Duration::from_secs(10),
+            frontier_size: 5,
+            count_engines: 2,
+            count_busy_engines: 1,
+            engines: vec![EngineActivity {
+                id: 1,
+                url: Url::parse("http://example.net").unwrap(),
+                started: now - Duration::from_secs(3),
+            }],
+            ..Statistics::default()
+        };
+
+        assert_eq!(
+            render(&stats, Some(30), now),
+            vec![
+                "visited 20  collected 10/30  errors 0  retries 0",
+                "frontier 5  retry pool 0  engines 1/2",
+                "2.0 pages/s  1.0 collected/s  elapsed 00:00:10  eta 00:00:20",
+                "engine 1   00:00:03  http://example.net/",
+            ]
+        );
+
+        let lines = render(&Statistics::default(), None, now);
+        assert_eq!(
+            lines[2],
+            "0.0 pages/s  0.0 collected/s  elapsed 00:00:00  eta -"
+        );
+    }
+
+    #[test]
+    fn truncate_url() {
+        assert_eq!(truncate("http://example.net", 56), "http://example.net");
+        assert_eq!(truncate("http://example.net", 10), "http://...");
+    }
+}
@@ -0,0 +1,652 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Replaying of previously captured crawls.
+//!
+//! An archive is either a directory with WARC files, which might be compressed per record,
+//! or a directory with saved pages and a manifest.
+//! Pages are loaded into a browser session instead of requesting a site
+//! so a check script can be developed fully offline.
+//! A content security policy of a loaded page forbids any request,
+//! so neither subresources nor requests of a check script reach a site.
+
+use crate::{
+    artifacts::MANIFEST_FILE,
+    backend::{
+        collect_links, Backend, BackendError, NotArchived, OpenAddress, RunningScript, SearchResult,
+    },
+};
+use async_trait::async_trait;
+use flate2::bufread::{GzDecoder, MultiGzDecoder, ZlibDecoder};
+use log::warn;
+use serde_json::{json, Value};
+use snafu::{OptionExt, ResultExt};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use thirtyfour::{WebDriver, WebDriverCommands};
+use url::Url;
+
+/// An index of archived pages.
+#[derive(Debug, Default)]
+pub struct Archive {
+    pages: HashMap<Url, Location>,
+}
+
+#[derive(Debug, Clone)]
+enum Location {
+    File(PathBuf),
+    Warc {
+        path: PathBuf,
+        /// An offset of a gzip member with a record if a file is compressed.
+        member: Option<u64>,
+        /// An offset of a record's block in a file or in an uncompressed member.
+        offset: u64,
+        length: u64,
+        is_response: bool,
+    },
+}
+
+impl Archive {
+    /// Opens an archive.
+    /// If there's a manifest in the directory it's considered to be a directory of saved pages,
+    /// otherwise all `.warc` and `.warc.gz` files in it are indexed.
+    /// A path to a single WARC file is allowed as well.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let mut archive = Self::default();
+
+        if path.is_file() {
+            archive.index_warc(path)?;
+        } else if path.join(MANIFEST_FILE).exists() {
+            archive.index_manifest(path)?;
+        } else {
+            let mut files = std::fs::read_dir(path)?
+                .map(|entry| entry.map(|e| e.path()))
+                .collect::<io::Result<Vec<_>>>()?;
+            files.retain(|file| is_warc(file) || is_gzipped_warc(file));
+            // so the latest capture of a page wins
+            files.sort();
+
+            for file in files {
+                archive.index_warc(&file)?;
+            }
+        }
+
+        Ok(archive)
+    }
+
+    pub fn urls(&self) -> impl Iterator<Item = &Url> {
+        self.pages.keys()
+    }
+
+    pub fn len(&self) -> usize {
+        self.pages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pages.is_empty()
+    }
+
+    pub fn load(&self, url: &Url) -> io::Result<Option<String>> {
+        let location = match self.pages.get(url) {
+            Some(location) => location,
+            None => return Ok(None),
+        };
+
+        let content = match location {
+            Location::File(path) => std::fs::read(path)?,
+            Location::Warc {
+                path,
+                member,
+                offset,
+                length,
+                is_response,
+            } => {
+                let mut file = File::open(path)?;
+                let mut block = vec![0; *length as usize];
+                match member {
+                    Some(member) => {
+                        file.seek(SeekFrom::Start(*member))?;
+                        let mut record = GzDecoder::new(BufReader::new(file));
+                        io::copy(&mut (&mut record).take(*offset), &mut io::sink())?;
+                        record.read_exact(&mut block)?;
+                    }
+                    None => {
+                        file.seek(SeekFrom::Start(*offset))?;
+                        file.read_exact(&mut block)?;
+                    }
+                }
+
+                if *is_response {
+                    http_body(block)?
+                } else {
+                    block
+                }
+            }
+        };
+
+        Ok(Some(String::from_utf8_lossy(&content).into_owned()))
+    }
+
+    fn index_manifest(&mut self, dir: &Path) -> io::Result<()> {
+        let manifest = BufReader::new(File::open(dir.join(MANIFEST_FILE))?);
+        for line in manifest.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let record: Value = serde_json::from_str(&line)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let url = record["url"].as_str().and_then(|url| Url::parse(url).ok());
+            let file = record["file"].as_str();
+            if let (Some(url), Some(file)) = (url, file) {
+                self.pages.insert(url, Location::File(dir.join(file)));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn index_warc(&mut self, path: &Path) -> io::Result<()> {
+        let mut reader = BufReader::new(File::open(path)?);
+        if !is_gzipped_warc(path) {
+            return self.index_records(&mut reader, path, None);
+        }
+
+        // each record is compressed separately so it's read without decompressing a whole file
+        loop {
+            let member = reader.stream_position()?;
+            if reader.fill_buf()?.is_empty() {
+                break;
+            }
+
+            let mut records = BufReader::new(GzDecoder::new(&mut reader));
+            self.index_records(&mut records, path, Some(member))?;
+        }
+
+        Ok(())
+    }
+
+    /// Indexes records of a reader till its end.
+    /// Offsets are relative to the reader's start.
+    fn index_records(
+        &mut self,
+        reader: &mut impl BufRead,
+        path: &Path,
+        member: Option<u64>,
+    ) -> io::Result<()> {
+        let mut offset = 0;
+        let mut line = String::new();
+
+        loop {
+            line.clear();
+            let n = reader.read_line(&mut line)?;
+            if n == 0 {
+                break;
+            }
+            offset += n as u64;
+
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            if !line.starts_with("WARC/") {
+                return Err(invalid_warc(path, "a record doesn't start with a version"));
+            }
+
+            let mut headers = HashMap::new();
+            loop {
+                line.clear();
+                let n = reader.read_line(&mut line)?;
+                if n == 0 {
+                    return Err(invalid_warc(path, "unexpected end of a file"));
+                }
+                offset += n as u64;
+
+                let header = line.trim_end();
+                if header.is_empty() {
+                    break;
+                }
+
+                if let Some((name, value)) = header.split_once(':') {
+                    headers.insert(name.trim().to_lowercase(), value.trim().to_owned());
+                }
+            }
+
+            let length = headers
+                .get("content-length")
+                .and_then(|length| length.parse::<u64>().ok())
+                .ok_or_else(|| invalid_warc(path, "a record has no Content-Length"))?;
+            let kind = headers.get("warc-type").map(|kind| kind.as_str());
+            let url = headers
+                .get("warc-target-uri")
+                .and_then(|url| Url::parse(url.trim_matches(|c| c == '<' || c == '>')).ok());
+
+            match (kind, url) {
+                (Some(kind), Some(url)) if kind == "resource" || kind == "response" => {
+                    let location = Location::Warc {
+                        path: path.to_owned(),
+                        member,
+                        offset,
+                        length,
+                        is_response: kind == "response",
+                    };
+                    self.pages.insert(url, location);
+                }
+                _ => (),
+            }
+
+            let skipped = io::copy(&mut reader.by_ref().take(length), &mut io::sink())?;
+            if skipped < length {
+                return Err(invalid_warc(path, "unexpected end of a file"));
+            }
+            offset += length;
+        }
+
+        Ok(())
+    }
+}
+
+pub struct ReplayBackend {
+    driver: WebDriver,
+    code: String,
+    archive: Arc<Archive>,
+}
+
+#[async_trait]
+impl Backend for ReplayBackend {
+    async fn search(&mut self, url: &Url) -> Result<SearchResult, BackendError> {
+        let html = self
+            .archive
+            .load(url)
+            .map_err(|e| BackendError::Other {
+                msg: format!("Unable to read an archive for {}: {}", url, e),
+            })?
+            .context(NotArchived {
+                address: url.clone(),
+            })?;
+
+        self.driver.get("about:blank").await.context(OpenAddress {
+            address: url.clone(),
+        })?;
+        self.driver
+            .execute_script(&load_script(url, &html))
+            .await
+            .context(OpenAddress {
+                address: url.clone(),
+            })?;
+
+        let urls = collect_links(&self.driver, url).await?;
+
+        let data = self
+            .driver
+            .execute_script(&replay_script(url, &self.code))
+            .await
+            .context(RunningScript {
+                address: url.clone(),
+            })?
+            .value()
+            .clone();
+
+        Ok(SearchResult::new(urls, data))
+    }
+
+    async fn close(self) {
+        if let Err(err) = self.driver.quit().await {
+            warn!("Failed to quit a session {}", err);
+        }
+    }
+}
+
+impl ReplayBackend {
+    pub fn new(driver: WebDriver, code: String, archive: Arc<Archive>) -> Self {
+        Self {
+            driver,
+            code,
+            archive,
+        }
+    }
+}
+
+/// A policy which allows inline scripts and styles but forbids any request.
+const OFFLINE_POLICY: &str = "default-src 'none'; script-src 'unsafe-inline' 'unsafe-eval'; \
+    style-src 'unsafe-inline'; img-src data:; font-src data:";
+
+fn load_script(url: &Url, html: &str) -> String {
+    format!(
+        r#"
+        document.open();
+        document.write({html});
+        document.close();
+        const base = document.createElement("base");
+        base.href = {url};
+        document.head.prepend(base);
+        "#,
+        html = json!(offline_html(html)),
+        url = json!(url.as_str()),
+    )
+}
+
+/// Puts a content security policy into a head of a page so it applies before any resource.
+fn offline_html(html: &str) -> String {
+    let meta = format!(
+        r#"<meta http-equiv="Content-Security-Policy" content="{}">"#,
+        OFFLINE_POLICY
+    );
+    // tags are ASCII so positions in a lowercased copy are the same
+    let lowercase = html.to_ascii_lowercase();
+    let position = ["<head", "<html"].iter().find_map(|tag| {
+        lowercase.match_indices(tag).find_map(|(start, _)| {
+            // `<header>` isn't `<head>`
+            let rest = &lowercase[start + tag.len()..];
+            if !rest.starts_with(|c: char| c == '>' || c.is_ascii_whitespace()) {
+                return None;
+            }
+
+            rest.find('>').map(|end| start + tag.len() + end + 1)
+        })
+    });
+
+    match position {
+        Some(position) => format!("{}{}{}", &html[..position], meta, &html[position..]),
+        None => format!("{}{}", meta, html),
+    }
+}
+
+/// Wraps a check script so `location` and `window.location` refer to an original page
+/// while it's actually loaded from `about:blank`.
+fn replay_script(url: &Url, code: &str) -> String {
+    format!(
+        r#"
+        const location = new URL({url});
+        const window = new Proxy(globalThis, {{
+            get(target, property) {{
+                if (property === "location") {{
+                    return location;
+                }}
+
+                const value = target[property];
+                return typeof value === "function" ? value.bind(target) : value;
+            }},
+        }});
+        return (function () {{
+            {code}
+        }})();
+        "#,
+        url = json!(url.as_str()),
+        code = code,
+    )
+}
+
+fn is_warc(path: &Path) -> bool {
+    path.extension().map_or(false, |ext| ext == "warc")
+}
+
+fn is_gzipped_warc(path: &Path) -> bool {
+    path.to_str()
+        .map_or(false, |path| path.ends_with(".warc.gz"))
+}
+
+/// Returns a body of an HTTP response decoding its transfer and content encodings.
+fn http_body(block: Vec<u8>) -> io::Result<Vec<u8>> {
+    let pos = match block.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(pos) => pos,
+        None => return Ok(block),
+    };
+
+    let head = String::from_utf8_lossy(&block[..pos]).to_lowercase();
+    let header = |name: &str| {
+        head.lines()
+            .skip(1)
+            .filter_map(|line| line.split_once(':'))
+            .find(|(header, _)| header.trim() == name)
+            .map(|(_, value)| value.trim().to_owned())
+    };
+
+    let mut body = block[pos + 4..].to_vec();
+    if header("transfer-encoding").map_or(false, |value| value.contains("chunked")) {
+        body = dechunk(&body)?;
+    }
+
+    let mut decoded = Vec::new();
+    match header("content-encoding").as_deref() {
+        Some("gzip") | Some("x-gzip") => {
+            MultiGzDecoder::new(body.as_slice()).read_to_end(&mut decoded)?;
+        }
+        Some("deflate") => {
+            ZlibDecoder::new(body.as_slice()).read_to_end(&mut decoded)?;
+        }
+        _ => return Ok(body),
+    }
+
+    Ok(decoded)
+}
+
+/// Joins chunks of a body which was sent with `Transfer-Encoding: chunked`.
+fn dechunk(mut body: &[u8]) -> io::Result<Vec<u8>> {
+    let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let mut decoded = Vec::new();
+    loop {
+        let pos = body
+            .windows(2)
+            .position(|w| w == b"\r\n")
+            .ok_or_else(|| invalid("a chunk has no size"))?;
+        let size = String::from_utf8_lossy(&body[..pos]);
+        // chunk extensions are ignored
+        let size = size.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| invalid("invalid chunk size"))?;
+        body = &body[pos + 2..];
+
+        if size == 0 {
+            return Ok(decoded);
+        }
+
+        if body.len() < size {
+            return Err(invalid("a chunk is truncated"));
+        }
+
+        decoded.extend_from_slice(&body[..size]);
+        body = body[size..].strip_prefix(b"\r\n").unwrap_or(&body[size..]);
+    }
+}
+
+fn invalid_warc(path: &Path, msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid WARC file {}: {}", path.display(), msg),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        engine::{Page, PageMetrics},
+        warc::{WarcConfig, WarcWriter},
+    };
+    use flate2::{write::GzEncoder, Compression};
+    use std::{fs, io::Write};
+
+    #[test]
+    fn open_warc() {
+        let tmp = crate::test_dir();
+        let dir = tmp.path();
+
+        let mut writer = WarcWriter::new(
+            WarcConfig {
+                dir: dir.to_owned(),
+                prefix: "test".to_owned(),
+                max_size: 1_000_000,
+            },
+            Vec::new(),
+        );
+        for (url, html) in &[
+            ("https://example.net/1", "<p>1</p>"),
+            ("https://example.net/2", "<p>2</p>"),
+        ] {
+            let page = Page {
+                url: Url::parse(url).unwrap(),
+                urls: Vec::new(),
+                data: Some(Value::Null),
+                source: Some(html.to_string()),
+                body: None,
+                status: None,
+                headers: Vec::new(),
+                metrics: PageMetrics::default(),
+            };
+            writer.write_page(&page).unwrap();
+        }
+
+        let archive = Archive::open(dir).unwrap();
+        assert_eq!(archive.len(), 2);
+        assert_eq!(
+            archive
+                .load(&Url::parse("https://example.net/2").unwrap())
+                .unwrap(),
+            Some("<p>2</p>".to_owned())
+        );
+        assert_eq!(
+            archive
+                .load(&Url::parse("https://example.net/3").unwrap())
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn open_pages_dir() {
+        let tmp = crate::test_dir();
+        let dir = tmp.path();
+        fs::write(dir.join("a.html"), "<p>a</p>").unwrap();
+        fs::write(
+            dir.join(MANIFEST_FILE),
+            "{\"url\":\"https://example.net/a\",\"file\":\"a.html\",\"kind\":\"dom\",\"time\":0}\n",
+        )
+        .unwrap();
+
+        let archive = Archive::open(dir).unwrap();
+        assert_eq!(archive.len(), 1);
+        assert_eq!(
+            archive
+                .load(&Url::parse("https://example.net/a").unwrap())
+                .unwrap(),
+            Some("<p>a</p>".to_owned())
+        );
+    }
+
+    #[test]
+    fn strip_headers_of_response() {
+        assert_eq!(
+            http_body(b"HTTP/1.1 200 OK\r\nContent-Type: text/html\r\n\r\n<p></p>".to_vec())
+                .unwrap(),
+            b"<p></p>".to_vec()
+        );
+    }
+
+    #[test]
+    fn offline_page() {
+        let meta = format!(
+            r#"<meta http-equiv="Content-Security-Policy" content="{}">"#,
+            OFFLINE_POLICY
+        );
+        assert_eq!(
+            offline_html("<!DOCTYPE html><HTML><Head lang=en><script src=/a.js></script>"),
+            format!(
+                "<!DOCTYPE html><HTML><Head lang=en>{}<script src=/a.js></script>",
+                meta
+            )
+        );
+        assert_eq!(
+            offline_html("<html><body><img src=a.png>"),
+            format!("<html>{}<body><img src=a.png>", meta)
+        );
+        assert_eq!(offline_html("<p>1</p>"), format!("{}<p>1</p>", meta));
+        assert_eq!(
+            offline_html("<header>1</header>"),
+            format!("{}<header>1</header>", meta)
+        );
+
+        // no request leaves an archive, `fetch` and XHR included
+        assert!(OFFLINE_POLICY.starts_with("default-src 'none';"));
+        assert!(!OFFLINE_POLICY.contains("connect-src"));
+        assert!(
+            load_script(&Url::parse("https://example.net").unwrap(), "<p>1</p>")
+                .contains("Content-Security-Policy")
+        );
+    }
+
+    #[test]
+    fn decode_chunked_body() {
+        assert_eq!(
+            http_body(
+                b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\n<p>\r\n4;x=y\r\n</p>\r\n0\r\n\r\n"
+                    .to_vec()
+            )
+            .unwrap(),
+            b"<p></p>".to_vec()
+        );
+    }
+
+    #[test]
+    fn open_gzipped_warc() {
+        let tmp = crate::test_dir();
+        let dir = tmp.path();
+
+        let mut file = Vec::new();
+        let pages: &[(&str, &[u8])] = &[
+            (
+                "https://example.net/1",
+                b"HTTP/1.1 200 OK\r\nContent-Type: text/html\r\n\r\n<p>1</p>",
+            ),
+            (
+                "https://example.net/2",
+                b"HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\nTransfer-Encoding: chunked\r\n\r\n",
+            ),
+        ];
+        for (url, block) in pages {
+            let mut block = block.to_vec();
+            if url.ends_with('2') {
+                let body = gzip(b"<p>2</p>");
+                block.extend_from_slice(format!("{:x}\r\n", body.len()).as_bytes());
+                block.extend_from_slice(&body);
+                block.extend_from_slice(b"\r\n0\r\n\r\n");
+            }
+
+            let mut record = format!(
+                "WARC/1.1\r\nWARC-Type: response\r\nWARC-Target-URI: {}\r\nContent-Length: {}\r\n\r\n",
+                url,
+                block.len()
+            )
+            .into_bytes();
+            record.extend_from_slice(&block);
+            record.extend_from_slice(b"\r\n\r\n");
+            file.extend(gzip(&record));
+        }
+        fs::write(dir.join("test.warc.gz"), file).unwrap();
+
+        let archive = Archive::open(dir).unwrap();
+        assert_eq!(archive.len(), 2);
+        for (url, html) in &[
+            ("https://example.net/1", "<p>1</p>"),
+            ("https://example.net/2", "<p>2</p>"),
+        ] {
+            assert_eq!(
+                archive.load(&Url::parse(url).unwrap()).unwrap(),
+                Some(html.to_string())
+            );
+        }
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+}
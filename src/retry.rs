@@ -1,14 +1,50 @@
+use rand::Rng;
 use std::{
     collections::{BTreeMap, HashMap},
-    time::{Duration, SystemTime},
+    time::Duration,
 };
+// a clock of tokio is used so it might be paused in tests
+use tokio::time::Instant;
 use url::Url;
 
+pub const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(300);
+
 pub struct RetryPool {
     fire_time: Duration,
+    max_delay: Duration,
     count_retries: usize,
-    pool: BTreeMap<SystemTime, Vec<Url>>,
-    retry_count: HashMap<Url, usize>,
+    // a sequence number keeps urls which are scheduled on the same instant apart
+    pool: BTreeMap<(Instant, u64), Url>,
+    seq: u64,
+    attempts: HashMap<Url, Vec<Attempt>>,
+    failed: Vec<Url>,
+}
+
+/// A way a retry is scheduled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Schedule {
+    /// A retry after a fire time.
+    Fixed,
+    /// A retry after a fire time which is doubled on each attempt with a random jitter.
+    Backoff,
+    /// A retry after a time requested by a server.
+    After(Duration),
+}
+
+/// A failed attempt to visit a url.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attempt {
+    pub reason: String,
+    /// A delay of a next attempt.
+    /// It's `None` if no retries left.
+    pub delay: Option<Duration>,
+}
+
+/// A url which was not visited after all retries.
+#[derive(Debug, Clone)]
+pub struct RetryFailure {
+    pub url: Url,
+    pub attempts: Vec<Attempt>,
 }
 
 impl RetryPool {
@@ -16,47 +52,112 @@ impl RetryPool {
         Self {
             fire_time,
             count_retries,
+            max_delay: DEFAULT_MAX_DELAY,
             pool: BTreeMap::new(),
-            retry_count: HashMap::new(),
+            seq: 0,
+            attempts: HashMap::new(),
+            failed: Vec::new(),
         }
     }
 
+    /// Sets a limit of a delay between attempts.
+    pub fn set_max_delay(&mut self, max_delay: Duration) {
+        self.max_delay = max_delay;
+    }
+
     pub fn keep_retry(&mut self, url: Url) -> bool {
-        let count = self.retry_count.entry(url.clone()).or_insert(0);
-        *count += 1;
-        if *count >= self.count_retries {
+        self.schedule(url, Schedule::Fixed, "")
+    }
+
+    pub fn keep_retry_with_backoff(&mut self, url: Url) -> bool {
+        self.schedule(url, Schedule::Backoff, "")
+    }
+
+    /// Schedules a retry of a url.
+    /// It returns `false` if there's no retries left for the url.
+    pub fn schedule(&mut self, url: Url, schedule: Schedule, reason: impl Into<String>) -> bool {
+        let attempts = self.attempts.entry(url.clone()).or_default();
+        let count = attempts.len() + 1;
+
+        if count >= self.count_retries {
+            attempts.push(Attempt {
+                reason: reason.into(),
+                delay: None,
+            });
+            self.failed.push(url);
             return false;
         }
 
-        let now = SystemTime::now();
-        let e = self.pool.entry(now).or_default();
-        e.push(url);
+        let delay = match schedule {
+            Schedule::Fixed => self.fire_time,
+            Schedule::Backoff => jitter(backoff(self.fire_time, count)),
+            Schedule::After(delay) => delay,
+        };
+        let delay = delay.min(self.max_delay);
+
+        attempts.push(Attempt {
+            reason: reason.into(),
+            delay: Some(delay),
+        });
+
+        self.seq += 1;
+        self.pool.insert((Instant::now() + delay, self.seq), url);
 
         true
     }
 
-    pub fn get_url(&mut self, force: bool) -> Option<Url> {
+    /// Takes a url which retry time has come.
+    pub fn get_url(&mut self) -> Option<Url> {
         // get the most close to be ready url
         let key = self
             .pool
             .keys()
             .next()
-            .filter(|time| time.elapsed().unwrap() > self.fire_time || force)
-            .cloned();
-
-        match key {
-            Some(time) => match self.pool[&time].len() {
-                0 => None,
-                1 => Some(self.pool.remove(&time).unwrap().pop().unwrap()),
-                _ => self.pool.get_mut(&time).unwrap().pop(),
-            },
-            None => None,
-        }
+            .filter(|(time, _)| *time <= Instant::now())
+            .cloned()?;
+
+        self.pool.remove(&key)
     }
 
     pub fn is_empty(&self) -> bool {
         self.pool.is_empty()
     }
+
+    pub fn len(&self) -> usize {
+        self.pool.len()
+    }
+
+    pub fn history(&self, url: &Url) -> &[Attempt] {
+        self.attempts.get(url).map_or(&[], |attempts| attempts)
+    }
+
+    /// Returns urls which ran out of retries.
+    pub fn failures(&self) -> Vec<RetryFailure> {
+        self.failed
+            .iter()
+            .map(|url| RetryFailure {
+                url: url.clone(),
+                attempts: self.history(url).to_vec(),
+            })
+            .collect()
+    }
+}
+
+fn backoff(base: Duration, attempt: usize) -> Duration {
+    let factor = 2u32.saturating_pow(attempt.saturating_sub(1) as u32);
+    base.checked_mul(factor).unwrap_or(Duration::MAX)
+}
+
+/// Returns a random delay between a half of a given one and the given one
+/// so retries of many urls don't fire at once.
+fn jitter(delay: Duration) -> Duration {
+    let half = delay / 2;
+    let millis = half.as_millis() as u64;
+    if millis == 0 {
+        return delay;
+    }
+
+    half + Duration::from_millis(rand::thread_rng().gen_range(0..=millis))
 }
 
 #[cfg(test)]
@@ -70,31 +171,32 @@ mod tests {
         pool.keep_retry(Url::parse("https://example_2.net").unwrap());
         pool.keep_retry(Url::parse("https://example_3.net").unwrap());
         assert_eq!(
-            pool.get_url(false),
+            pool.get_url(),
             Some(Url::parse("https://example_1.net").unwrap())
         );
         assert_eq!(
-            pool.get_url(false),
+            pool.get_url(),
             Some(Url::parse("https://example_2.net").unwrap())
         );
         assert_eq!(
-            pool.get_url(false),
+            pool.get_url(),
             Some(Url::parse("https://example_3.net").unwrap())
         );
-        assert_eq!(pool.get_url(false), None);
+        assert_eq!(pool.get_url(), None);
     }
 
-    #[test]
-    fn get_with_fire() {
+    #[tokio::test]
+    async fn get_with_fire() {
+        tokio::time::pause();
         let mut pool = RetryPool::new(Duration::from_millis(50), 2);
         pool.keep_retry(Url::parse("https://example_1.net").unwrap());
-        assert_eq!(pool.get_url(false), None);
-        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(pool.get_url(), None);
+        tokio::time::advance(Duration::from_millis(50)).await;
         assert_eq!(
-            pool.get_url(false),
+            pool.get_url(),
             Some(Url::parse("https://example_1.net").unwrap())
         );
-        assert_eq!(pool.get_url(false), None);
+        assert_eq!(pool.get_url(), None);
     }
 
     #[test]
@@ -105,24 +207,111 @@ mod tests {
             let is_not_over = pool.keep_retry(Url::parse("https://example_1.net").unwrap());
             assert_eq!(is_not_over, true);
             assert_eq!(
-                pool.get_url(false),
+                pool.get_url(),
                 Some(Url::parse("https://example_1.net").unwrap())
             );
         }
 
         let is_not_over = pool.keep_retry(Url::parse("https://example_1.net").unwrap());
         assert_eq!(is_not_over, false);
-        assert_eq!(pool.get_url(false), None);
+        assert_eq!(pool.get_url(), None);
+    }
+
+    #[tokio::test]
+    async fn get_with_backoff() {
+        tokio::time::pause();
+        let mut pool = RetryPool::new(Duration::from_millis(40), 4);
+        let url = Url::parse("https://example_1.net").unwrap();
+
+        // the first delay is within [20ms, 40ms]
+        assert!(pool.keep_retry_with_backoff(url.clone()));
+        tokio::time::advance(Duration::from_millis(40)).await;
+        assert_eq!(pool.get_url(), Some(url.clone()));
+
+        // the second delay is within [40ms, 80ms]
+        assert!(pool.keep_retry_with_backoff(url.clone()));
+        tokio::time::advance(Duration::from_millis(30)).await;
+        assert_eq!(pool.get_url(), None);
+        tokio::time::advance(Duration::from_millis(50)).await;
+        assert_eq!(pool.get_url(), Some(url.clone()));
+
+        let delays = pool
+            .history(&url)
+            .iter()
+            .map(|a| a.delay.unwrap())
+            .collect::<Vec<_>>();
+        assert!(delays[0] >= Duration::from_millis(20) && delays[0] <= Duration::from_millis(40));
+        assert!(delays[1] >= Duration::from_millis(40) && delays[1] <= Duration::from_millis(80));
+    }
+
+    #[tokio::test]
+    async fn get_with_retry_after() {
+        tokio::time::pause();
+        let mut pool = RetryPool::new(Duration::from_secs(10), 2);
+        let url = Url::parse("https://example_1.net").unwrap();
+
+        assert!(pool.schedule(
+            url.clone(),
+            Schedule::After(Duration::from_millis(20)),
+            "429"
+        ));
+        assert_eq!(pool.get_url(), None);
+        tokio::time::advance(Duration::from_millis(20)).await;
+        assert_eq!(pool.get_url(), Some(url));
     }
 
     #[test]
-    fn get_force() {
-        let mut pool = RetryPool::new(Duration::from_millis(50), 2);
-        pool.keep_retry(Url::parse("https://example_1.net").unwrap());
+    fn delay_is_limited() {
+        let mut pool = RetryPool::new(Duration::from_secs(10), 2);
+        pool.set_max_delay(Duration::from_millis(10));
+        let url = Url::parse("https://example_1.net").unwrap();
+
+        assert!(pool.schedule(url.clone(), Schedule::After(Duration::from_secs(3600)), ""));
+        assert_eq!(pool.history(&url)[0].delay, Some(Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn failures_report() {
+        let mut pool = RetryPool::new(Duration::default(), 2);
+        let url = Url::parse("https://example_1.net").unwrap();
+
+        assert!(pool.schedule(url.clone(), Schedule::Fixed, "timeout"));
+        assert!(!pool.schedule(url.clone(), Schedule::Fixed, "dns"));
+
+        let failures = pool.failures();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].url, url);
         assert_eq!(
-            pool.get_url(true),
-            Some(Url::parse("https://example_1.net").unwrap())
+            failures[0].attempts,
+            vec![
+                Attempt {
+                    reason: "timeout".to_owned(),
+                    delay: Some(Duration::default()),
+                },
+                Attempt {
+                    reason: "dns".to_owned(),
+                    delay: None,
+                },
+            ]
         );
-        assert_eq!(pool.get_url(false), None);
+    }
+
+    #[tokio::test]
+    async fn get_waits_for_retry_after() {
+        tokio::time::pause();
+        let mut pool = RetryPool::new(Duration::from_millis(50), 2);
+        let url = Url::parse("https://example_1.net").unwrap();
+
+        // nothing else is queued, still the url waits for its time
+        assert!(pool.schedule(
+            url.clone(),
+            Schedule::After(Duration::from_secs(120)),
+            "429"
+        ));
+        tokio::time::advance(Duration::from_secs(119)).await;
+        assert_eq!(pool.get_url(), None);
+        assert!(!pool.is_empty());
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_eq!(pool.get_url(), Some(url));
     }
 }
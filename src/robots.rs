@@ -1,4 +1,5 @@
 use cylon::{Compiler, Cylon};
+use reqwest::Client;
 use std::{collections::HashMap, io};
 use url::Url;
 
@@ -6,12 +7,21 @@ use url::Url;
 pub struct RobotsMap {
     map: HashMap<(Domain, Robot), RobotsVerifier>,
     files: HashMap<Robot, Vec<u8>>,
+    client: Client,
 }
 
 type Domain = String;
 type Robot = String;
 
 impl RobotsMap {
+    pub fn new(client: Client) -> Self {
+        Self {
+            client,
+            map: HashMap::new(),
+            files: HashMap::new(),
+        }
+    }
+
     pub async fn is_allowed(&mut self, robot: &str, url: Url) -> io::Result<bool> {
         let domain = match url.domain() {
             Some(domain) => domain,
@@ -43,7 +53,10 @@ impl RobotsMap {
             )
         })?;
 
-        let res = reqwest::get(robot_url)
+        let res = self
+            .client
+            .get(robot_url)
+            .send()
             .await
             .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
             .bytes()
@@ -0,0 +1,426 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A writer of WARC/1.1 files.
+//!
+//! For each visited page it writes a `request` record and a record of the page:
+//! a `response` one with a body the server sent if it's known, which is the case in Chrome,
+//! followed by a `conversion` one with a page changed by scripts,
+//! or otherwise a `resource` one with a captured page.
+//! Then `metadata` records with extracted data and outlinks are written.
+
+use crate::engine::Page;
+use chrono::{SecondsFormat, Utc};
+use hyper::StatusCode;
+use sha2::{Digest, Sha256};
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+};
+use url::{Position, Url};
+use uuid::Uuid;
+
+pub const DEFAULT_MAX_SIZE: u64 = 1_000_000_000;
+
+#[derive(Debug, Clone)]
+pub struct WarcConfig {
+    pub dir: PathBuf,
+    pub prefix: String,
+    /// A size in bytes after crossing which a new file is started.
+    pub max_size: u64,
+}
+
+#[derive(Debug)]
+pub struct WarcWriter {
+    cfg: WarcConfig,
+    request_headers: Vec<(String, String)>,
+    file: Option<File>,
+    size: u64,
+    count_pages: usize,
+    serial: usize,
+}
+
+impl WarcWriter {
+    pub fn new(cfg: WarcConfig, request_headers: Vec<(String, String)>) -> Self {
+        Self {
+            cfg,
+            request_headers,
+            file: None,
+            size: 0,
+            count_pages: 0,
+            serial: 0,
+        }
+    }
+
+    pub fn write_page(&mut self, page: &Page) -> io::Result<()> {
+        let date = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true);
+        let target = page.url.as_str();
+
+        // a response is written only with a body the server sent,
+        // a source is a DOM otherwise
+        let response = match (page.body.as_ref(), page.status) {
+            (Some(body), Some(status)) if !page.headers.is_empty() => Some((status, body)),
+            _ => None,
+        };
+        let page_id = if response.is_some() || page.source.is_some() {
+            Some(record_id())
+        } else {
+            None
+        };
+
+        let mut headers = vec![("WARC-Target-URI", target)];
+        if let Some(id) = page_id.as_ref() {
+            headers.push(("WARC-Concurrent-To", id.as_str()));
+        }
+
+        let mut records = Vec::new();
+        records.extend(record(
+            "request",
+            &date,
+            &headers,
+            "application/http;msgtype=request",
+            self.request_block(&page.url).as_bytes(),
+        ));
+
+        let page_headers = match page_id.as_ref() {
+            Some(id) => vec![("WARC-Target-URI", target), ("WARC-Record-ID", id.as_str())],
+            None => Vec::new(),
+        };
+        let refers_headers = match page_id.as_ref() {
+            Some(id) => vec![("WARC-Target-URI", target), ("WARC-Refers-To", id.as_str())],
+            None => vec![("WARC-Target-URI", target)],
+        };
+
+        match (response, page.source.as_ref()) {
+            (Some((status, body)), source) => {
+                records.extend(record(
+                    "response",
+                    &date,
+                    &page_headers,
+                    "application/http;msgtype=response",
+                    &response_block(status, &page.headers, body),
+                ));
+
+                // a page changed by scripts is kept as a conversion of the response
+                if let Some(source) = source.filter(|source| *source != body) {
+                    records.extend(record(
+                        "conversion",
+                        &date,
+                        &refers_headers,
+                        "text/html; charset=utf-8",
+                        source.as_bytes(),
+                    ));
+                }
+            }
+            (None, Some(source)) => {
+                records.extend(record(
+                    "resource",
+                    &date,
+                    &page_headers,
+                    "text/html; charset=utf-8",
+                    source.as_bytes(),
+                ));
+            }
+            (None, None) => (),
+        }
+
+        if let Some(data) = page.data.as_ref() {
+            records.extend(record(
+                "metadata",
+                &date,
+                &refers_headers,
+                "application/json",
+                data.to_string().as_bytes(),
+            ));
+        }
+
+        let outlinks = page
+            .urls
+            .iter()
+            .map(|url| format!("outlink: {}\r\n", url))
+            .collect::<String>();
+        records.extend(record(
+            "metadata",
+            &date,
+            &refers_headers,
+            "application/warc-fields",
+            outlinks.as_bytes(),
+        ));
+
+        self.write(&records)
+    }
+
+    fn write(&mut self, content: &[u8]) -> io::Result<()> {
+        // a page is never split between files
+        // so a file may exceed the limit if a single page does
+        let is_full = self.size + content.len() as u64 > self.cfg.max_size;
+        if self.file.is_none() || (is_full && self.count_pages > 0) {
+            self.rotate()?;
+        }
+
+        let file = self.file.as_mut().unwrap();
+        file.write_all(content)?;
+        file.flush()?;
+        self.size += content.len() as u64;
+        self.count_pages += 1;
+
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let (name, mut file) = loop {
+            let name = format!(
+                "{}-{}-{:05}.warc",
+                self.cfg.prefix,
+                Utc::now().format("%Y%m%d%H%M%S"),
+                self.serial
+            );
+            self.serial += 1;
+
+            let path = self.cfg.dir.join(&name);
+            match OpenOptions::new().write(true).create_new(true).open(path) {
+                Ok(file) => break (name, file),
+                // another crawl with the same prefix started a file in the same second
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(err),
+            }
+        };
+
+        let date = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true);
+        let fields = format!(
+            "software: doonop/{}\r\nformat: WARC File Format 1.1\r\n",
+            env!("CARGO_PKG_VERSION")
+        );
+        let info = record(
+            "warcinfo",
+            &date,
+            &[("WARC-Filename", name.as_str())],
+            "application/warc-fields",
+            fields.as_bytes(),
+        );
+        file.write_all(&info)?;
+
+        self.file = Some(file);
+        self.size = info.len() as u64;
+        self.count_pages = 0;
+
+        Ok(())
+    }
+
+    fn request_block(&self, url: &Url) -> String {
+        let mut host = url.host_str().unwrap_or_default().to_owned();
+        if let Some(port) = url.port() {
+            host.push_str(&format!(":{}", port));
+        }
+
+        let mut block = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\n",
+            &url[Position::BeforePath..Position::AfterQuery],
+            host
+        );
+        for (name, value) in &self.request_headers {
+            block.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        block.push_str("\r\n");
+
+        block
+    }
+}
+
+fn record(
+    kind: &str,
+    date: &str,
+    headers: &[(&str, &str)],
+    content_type: &str,
+    block: &[u8],
+) -> Vec<u8> {
+    let mut head = format!("WARC/1.1\r\nWARC-Type: {}\r\nWARC-Date: {}\r\n", kind, date);
+    if !headers.iter().any(|(name, _)| *name == "WARC-Record-ID") {
+        head.push_str(&format!("WARC-Record-ID: {}\r\n", record_id()));
+    }
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str(&format!(
+        "WARC-Block-Digest: sha256:{:x}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n",
+        Sha256::digest(block),
+        content_type,
+        block.len()
+    ));
+
+    let mut record = head.into_bytes();
+    record.extend_from_slice(block);
+    record.extend_from_slice(b"\r\n\r\n");
+    record
+}
+
+/// Makes an HTTP response out of a captured page.
+/// Headers which describe a transfer are replaced
+/// as a captured page is already decoded.
+fn response_block(status: u16, headers: &[(String, String)], source: &str) -> Vec<u8> {
+    // a reason phrase is required by a status line, an unknown status has an empty one
+    let reason = StatusCode::from_u16(status)
+        .ok()
+        .and_then(|status| status.canonical_reason())
+        .unwrap_or("");
+    let mut block = format!("HTTP/1.1 {} {}\r\n", status, reason);
+    for (name, value) in headers {
+        let name_lowercase = name.to_lowercase();
+        if name_lowercase == "content-length"
+            || name_lowercase == "content-encoding"
+            || name_lowercase == "transfer-encoding"
+        {
+            continue;
+        }
+
+        block.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    block.push_str(&format!("Content-Length: {}\r\n\r\n", source.len()));
+
+    let mut block = block.into_bytes();
+    block.extend_from_slice(source.as_bytes());
+    block
+}
+
+fn record_id() -> String {
+    format!("<urn:uuid:{}>", Uuid::new_v4())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::PageMetrics;
+    use serde_json::json;
+    use std::fs;
+
+    #[test]
+    fn record_format() {
+        let r = record(
+            "resource",
+            "2021-07-20T21:19:17Z",
+            &[("WARC-Target-URI", "https://example.net/")],
+            "text/html",
+            b"<p></p>",
+        );
+        let r = String::from_utf8(r).unwrap();
+
+        assert!(
+            r.starts_with("WARC/1.1\r\nWARC-Type: resource\r\nWARC-Date: 2021-07-20T21:19:17Z\r\n")
+        );
+        assert!(r.contains("WARC-Record-ID: <urn:uuid:"));
+        assert!(r.contains("WARC-Target-URI: https://example.net/\r\n"));
+        assert!(r.ends_with("Content-Type: text/html\r\nContent-Length: 7\r\n\r\n<p></p>\r\n\r\n"));
+    }
+
+    #[test]
+    fn write_with_rotation() {
+        let tmp = crate::test_dir();
+        let dir = tmp.path();
+
+        let mut writer = WarcWriter::new(
+            WarcConfig {
+                dir: dir.to_owned(),
+                prefix: "test".to_owned(),
+                max_size: 2000,
+            },
+            vec![("User-Agent".to_owned(), "DoonopRobot".to_owned())],
+        );
+
+        let page = Page {
+            url: Url::parse("https://example.net/path?q=1").unwrap(),
+            urls: vec![Url::parse("https://example.net/other").unwrap()],
+            data: Some(json!({ "title": "t" })),
+            source: Some("<html></html>".to_owned()),
+            body: None,
+            status: None,
+            headers: Vec::new(),
+            metrics: PageMetrics::default(),
+        };
+        writer.write_page(&page).unwrap();
+        writer.write_page(&page).unwrap();
+
+        let mut files = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(files.len(), 2);
+
+        let content = fs::read_to_string(&files[0]).unwrap();
+        assert!(content.starts_with("WARC/1.1\r\nWARC-Type: warcinfo\r\n"));
+        assert!(content.contains(
+            "GET /path?q=1 HTTP/1.1\r\nHost: example.net\r\nUser-Agent: DoonopRobot\r\n"
+        ));
+        assert!(content.contains("WARC-Type: resource\r\n"));
+        assert!(content.contains("<html></html>"));
+        assert!(content.contains("{\"title\":\"t\"}"));
+        assert!(content.contains("outlink: https://example.net/other\r\n"));
+    }
+
+    #[test]
+    fn write_response_and_conversion() {
+        let tmp = crate::test_dir();
+        let dir = tmp.path();
+        let cfg = WarcConfig {
+            dir: dir.to_owned(),
+            prefix: "test".to_owned(),
+            max_size: DEFAULT_MAX_SIZE,
+        };
+
+        let page = Page {
+            url: Url::parse("https://example.net").unwrap(),
+            urls: Vec::new(),
+            data: None,
+            source: Some("<p>changed</p>".to_owned()),
+            body: Some("<p>sent</p>".to_owned()),
+            status: Some(200),
+            headers: vec![("Content-Type".to_owned(), "text/html".to_owned())],
+            metrics: PageMetrics::default(),
+        };
+        let empty = Page {
+            source: None,
+            body: None,
+            ..page.clone()
+        };
+        // writers with the same prefix don't overwrite files of each other
+        let mut writer = WarcWriter::new(cfg.clone(), Vec::new());
+        writer.write_page(&page).unwrap();
+        let mut other = WarcWriter::new(cfg, Vec::new());
+        other.write_page(&empty).unwrap();
+
+        let mut files = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| fs::read_to_string(e.unwrap().path()).unwrap())
+            .collect::<Vec<_>>();
+        files.sort_by_key(|content| !content.contains("WARC-Type: response"));
+
+        assert_eq!(files.len(), 2);
+        assert!(files[0].contains("WARC-Type: response\r\n"));
+        assert!(files[0].contains("HTTP/1.1 200 OK\r\nContent-Type: text/html\r\n"));
+        assert!(files[0].contains("\r\n\r\n<p>sent</p>"));
+        assert!(files[0].contains("WARC-Type: conversion\r\n"));
+        assert!(files[0].contains("<p>changed</p>"));
+        assert!(!files[1].contains("WARC-Refers-To"));
+        assert!(!files[1].contains("WARC-Concurrent-To"));
+    }
+
+    #[test]
+    fn response_block_replaces_transfer_headers() {
+        let block = response_block(
+            404,
+            &[
+                ("Content-Type".to_owned(), "text/html".to_owned()),
+                ("Content-Encoding".to_owned(), "gzip".to_owned()),
+                ("content-length".to_owned(), "5".to_owned()),
+            ],
+            "<p>Not Found</p>",
+        );
+
+        assert_eq!(
+            String::from_utf8(block).unwrap(),
+            "HTTP/1.1 404 Not Found\r\nContent-Type: text/html\r\nContent-Length: 16\r\n\r\n<p>Not Found</p>"
+        );
+    }
+}
@@ -3,35 +3,144 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use crate::{
-    backend::{Backend, BackendError},
-    engine::{Engine, EngineId},
+    backend::{Backend, BackendError, ErrorClass},
+    crawler::Sink,
+    engine::{Engine, EngineId, Page},
     engine_builder::EngineBuilder,
-    engine_ring::EngineRing,
-    retry::RetryPool,
+    engine_ring::{BuiltEngine, EngineRing},
+    filters::Filter,
+    frontier::{Frontier, MemoryFrontier},
+    observer::{CrawlObserver, Discovery, FilterReason, Observers},
+    retry::{RetryFailure, RetryPool, Schedule},
     robots::RobotsMap,
+    warc::WarcWriter,
 };
 use async_channel::{unbounded, Receiver, Sender};
 use log::{error, info};
-use serde_json::Value;
+use serde_json::{json, Map, Value};
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     io,
-    sync::Arc,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::{mpsc, watch, Notify},
+    task::JoinHandle,
 };
-use tokio::{sync::Notify, task::JoinHandle};
 use url::Url;
 
 pub struct Workload<B, EB> {
-    urls_pool: Vec<Url>,
+    frontier: Box<dyn Frontier>,
     retry_policy: RetryPolicy,
     retry_pool: RetryPool,
-    seen_list: HashSet<Url>,
-    url_limit: Option<usize>,
+    stop_conditions: StopConditions,
+    stop_reason: Option<StopReason>,
     robot_ctrl: RobotsMap,
     use_robot_check: bool,
     robot: String,
     spawned_jobs: HashMap<EngineId, JoinHandle<()>>,
     ring: EngineRing<B, EB>,
+    warc: Option<WarcWriter>,
+    error_policy: ErrorPolicy,
+    failed_domains: HashSet<String>,
+    filters: Vec<Filter>,
+    observers: Observers,
+    sinks: Vec<Box<dyn Sink>>,
+    live_stats: Option<watch::Sender<Statistics>>,
+    started: Instant,
+    /// Urls engines work on at the moment.
+    activity: Arc<Mutex<HashMap<EngineId, EngineActivity>>>,
+    control: Option<mpsc::UnboundedReceiver<Command>>,
+    is_paused: bool,
+    /// A channel of engines which are built or closed in background.
+    engine_events: mpsc::UnboundedSender<EngineEvent<B>>,
+    engine_event_receiver: Option<mpsc::UnboundedReceiver<EngineEvent<B>>>,
+    /// An amount of recycled engines which sessions are being closed.
+    count_recycling: usize,
+}
+
+/// An engine's background work which is reported back to a crawl.
+enum EngineEvent<B> {
+    Built(io::Result<BuiltEngine<B>>),
+    /// A recycled engine closed its session.
+    Recycled(EngineId),
+}
+
+/// A command which changes a running crawl.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// Stop dispatching urls to engines, pages which are being worked on are finished.
+    Pause,
+    Resume,
+    /// Add urls to the frontier, ones which were already seen are ignored.
+    AddSeeds(Vec<Url>),
+    /// Change an amount of data to collect, which includes already collected one.
+    /// It's the same as [`StopConditions::max_collected`].
+    SetLimit(Option<usize>),
+    /// Finish pages which are being worked on and stop.
+    Stop,
+}
+
+/// An interval in which statistics are published even if there's no new results,
+/// so watchers see how long engines work on their pages.
+const PUBLISH_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Conditions which stop a crawl when any of them is reached.
+/// Pages which engines finish after it are not counted as collected and their data is dropped,
+/// so a crawl collects exactly up to the limits.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct StopConditions {
+    pub max_duration: Option<Duration>,
+    pub max_visited: Option<usize>,
+    /// An amount of pages with data other than `null`.
+    pub max_collected: Option<usize>,
+}
+
+impl StopConditions {
+    fn is_limit_reached(&self, stats: &Statistics) -> Option<StopReason> {
+        if matches!(self.max_collected, Some(max) if stats.count_collected >= max) {
+            return Some(StopReason::MaxCollected);
+        }
+
+        if matches!(self.max_visited, Some(max) if stats.count_visited >= max) {
+            return Some(StopReason::MaxVisited);
+        }
+
+        None
+    }
+
+    fn check(&self, stats: &Statistics, elapsed: Duration) -> Option<StopReason> {
+        if matches!(self.max_duration, Some(max) if elapsed >= max) {
+            return Some(StopReason::MaxDuration);
+        }
+
+        self.is_limit_reached(stats)
+    }
+}
+
+/// A reason a crawl was stopped before urls ran out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// A stop was requested by a user.
+    Interrupted,
+    /// An error policy aborted a crawl.
+    Aborted,
+    MaxDuration,
+    MaxVisited,
+    MaxCollected,
+}
+
+impl StopReason {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Interrupted => "interrupted",
+            Self::Aborted => "aborted",
+            Self::MaxDuration => "max-duration",
+            Self::MaxVisited => "max-visited",
+            Self::MaxCollected => "max-collected",
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -41,247 +150,966 @@ pub enum RetryPolicy {
     No,
 }
 
-#[derive(Debug, Default)]
+/// An action which is taken on an error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorAction {
+    Retry,
+    /// A retry which is delayed more with each attempt.
+    Backoff,
+    Skip,
+    /// Stop visiting pages of the url's domain.
+    FailDomain,
+    /// Stop the crawl.
+    Abort,
+}
+
+#[derive(Debug, Clone)]
+pub struct ErrorPolicy {
+    actions: HashMap<ErrorClass, ErrorAction>,
+}
+
+impl ErrorPolicy {
+    pub fn action(&self, class: ErrorClass) -> ErrorAction {
+        self.actions
+            .get(&class)
+            .copied()
+            .unwrap_or(ErrorAction::Skip)
+    }
+
+    pub fn set(&mut self, class: ErrorClass, action: ErrorAction) {
+        self.actions.insert(class, action);
+    }
+}
+
+impl Default for ErrorPolicy {
+    fn default() -> Self {
+        let mut policy = Self {
+            actions: HashMap::new(),
+        };
+        policy.set(ErrorClass::Timeout, ErrorAction::Retry);
+        policy.set(ErrorClass::TooManyRequests, ErrorAction::Backoff);
+        policy.set(ErrorClass::ServerError, ErrorAction::Backoff);
+        policy.set(ErrorClass::SessionLost, ErrorAction::Retry);
+
+        policy
+    }
+}
+
+#[derive(Debug, Default, Clone)]
 pub struct Statistics {
     pub count_errors: usize,
     pub count_retries: usize,
     pub count_visited: usize,
     pub count_collected: usize,
+    /// An amount of engines which were rebuilt after their sessions were lost.
+    pub count_rebuilds: usize,
+    /// An amount of engines which failed to be built.
+    pub count_failed_engines: usize,
+    /// Health of proxies by their names.
+    pub proxies: HashMap<String, ProxyHealth>,
+    /// Urls which ran out of retries with their attempts.
+    pub failures: Vec<RetryFailure>,
+    /// Counters by hosts of urls.
+    pub hosts: HashMap<String, HostStatistics>,
+    /// Errors by their classes including ones which were retried.
+    pub errors: HashMap<ErrorClass, usize>,
+    pub load_time: Histogram,
+    pub script_time: Histogram,
+    /// An amount of bytes of pages which sizes are known.
+    pub count_bytes: u64,
+    /// An amount of links found on pages.
+    pub count_links: usize,
+    /// An amount of links which were dropped by filters.
+    pub count_filtered: usize,
+    /// An amount of urls which weren't visited as robots.txt disallowed them.
+    pub count_robots_denied: usize,
+    /// A time since a crawl started.
+    pub elapsed: Duration,
+    /// An amount of urls waiting to be visited.
+    pub frontier_size: usize,
+    /// An amount of urls waiting to be retried.
+    pub retry_pool_size: usize,
+    /// An amount of engines which might work at the moment.
+    pub count_engines: usize,
+    /// An amount of engines working on pages at the moment.
+    pub count_busy_engines: usize,
+    /// Engines working on pages at the moment ordered by their ids.
+    pub engines: Vec<EngineActivity>,
+    /// Dispatching urls is paused by a command.
+    pub is_paused: bool,
+    pub stop_reason: Option<StopReason>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EngineActivity {
+    pub id: EngineId,
+    pub url: Url,
+    pub started: Instant,
+}
+
+impl Statistics {
+    fn record_page(&mut self, page: &Page) {
+        let metrics = &page.metrics;
+        if let Some(load_time) = metrics.load_time {
+            self.load_time.record(load_time);
+        }
+        if let Some(script_time) = metrics.script_time {
+            self.script_time.record(script_time);
+        }
+        self.count_bytes += metrics.size.unwrap_or(0);
+        self.count_links += metrics.count_links;
+        self.count_filtered += metrics.count_filtered;
+    }
+
+    fn host(&mut self, url: &Url) -> &mut HostStatistics {
+        let host = url.host_str().unwrap_or_default().to_owned();
+        self.hosts.entry(host).or_default()
+    }
+
+    pub fn to_json(&self) -> Value {
+        let errors = self
+            .errors
+            .iter()
+            .map(|(class, count)| (class.name().to_owned(), json!(count)))
+            .collect::<Map<_, _>>();
+        let hosts = self
+            .hosts
+            .iter()
+            .map(|(host, stats)| {
+                let stats = json!({
+                    "visited": stats.count_visited,
+                    "collected": stats.count_collected,
+                    "errors": stats.count_errors,
+                });
+                (host.clone(), stats)
+            })
+            .collect::<Map<_, _>>();
+        let proxies = self
+            .proxies
+            .iter()
+            .map(|(proxy, health)| {
+                let health = json!({
+                    "visited": health.count_visited,
+                    "errors": health.count_errors,
+                    "blocks": health.count_blocks,
+                    "rotations": health.count_rotations,
+                });
+                (proxy.clone(), health)
+            })
+            .collect::<Map<_, _>>();
+        let failures = self
+            .failures
+            .iter()
+            .map(|failure| {
+                let attempts = failure
+                    .attempts
+                    .iter()
+                    .map(|attempt| {
+                        json!({
+                            "reason": attempt.reason,
+                            "delay_ms": attempt.delay.map(|delay| delay.as_millis() as u64),
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                json!({ "url": failure.url.as_str(), "attempts": attempts })
+            })
+            .collect::<Vec<_>>();
+
+        json!({
+            "visited": self.count_visited,
+            "collected": self.count_collected,
+            "errors": self.count_errors,
+            "retries": self.count_retries,
+            "rebuilds": self.count_rebuilds,
+            "failed_engines": self.count_failed_engines,
+            "bytes": self.count_bytes,
+            "links": self.count_links,
+            "filtered": self.count_filtered,
+            "robots_denied": self.count_robots_denied,
+            "elapsed_ms": self.elapsed.as_millis() as u64,
+            "paused": self.is_paused,
+            "stop_reason": self.stop_reason.map(|reason| reason.name()),
+            "errors_by_class": errors,
+            "hosts": hosts,
+            "proxies": proxies,
+            "load_time": self.load_time.to_json(),
+            "script_time": self.script_time.to_json(),
+            "failures": failures,
+        })
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct HostStatistics {
+    pub count_visited: usize,
+    pub count_collected: usize,
+    pub count_errors: usize,
+}
+
+/// A histogram of durations in milliseconds with log-linear buckets.
+/// Durations up to 32ms are exact, longer ones are split into
+/// `HISTOGRAM_SUB_BUCKETS` buckets per power of 2,
+/// so percentiles which are upper bounds of buckets are over by less than 7%.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Histogram {
+    /// Counts of durations by upper bounds of buckets in milliseconds.
+    buckets: BTreeMap<u64, usize>,
+    count: usize,
+    max: Duration,
+}
+
+const HISTOGRAM_SUB_BUCKETS: u64 = 16;
+
+impl Histogram {
+    pub fn record(&mut self, duration: Duration) {
+        let millis = (duration.as_millis() as u64).max(1);
+        *self.buckets.entry(bucket_bound(millis)).or_default() += 1;
+        self.count += 1;
+        self.max = self.max.max(duration);
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    /// Returns a duration which `q` part of durations doesn't exceed, where `q` is in `0..=1`.
+    pub fn percentile(&self, q: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let rank = ((q * self.count as f64).ceil() as usize).max(1);
+        let mut count = 0;
+        for (bound, bucket_count) in &self.buckets {
+            count += bucket_count;
+            if count >= rank {
+                return Some(Duration::from_millis(*bound).min(self.max));
+            }
+        }
+
+        Some(self.max)
+    }
+
+    fn to_json(&self) -> Value {
+        let millis = |q| self.percentile(q).map(|d| d.as_millis() as u64);
+        json!({
+            "count": self.count,
+            "p50_ms": millis(0.5),
+            "p95_ms": millis(0.95),
+            "p99_ms": millis(0.99),
+            "max_ms": self.max.as_millis() as u64,
+        })
+    }
+}
+
+/// Returns an upper bound of a bucket which keeps a duration in milliseconds.
+fn bucket_bound(millis: u64) -> u64 {
+    let value = millis - 1;
+    let bits = 64 - value.leading_zeros() as u64;
+    // a value is shifted so it's in `SUB_BUCKETS..2 * SUB_BUCKETS`
+    let shift = bits.saturating_sub(HISTOGRAM_SUB_BUCKETS.trailing_zeros() as u64 + 1);
+    ((value >> shift) + 1) << shift
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ProxyHealth {
+    pub count_visited: usize,
+    pub count_errors: usize,
+    /// An amount of pages which responded with 403 or 429 statuses, timed out
+    /// or which connections were refused.
+    pub count_blocks: usize,
+    /// An amount of times engines were moved off the proxy.
+    pub count_rotations: usize,
 }
 
 impl<B, EB> Workload<B, EB>
 where
-    EB: EngineBuilder<Backend = B>,
+    EB: EngineBuilder<Backend = B> + Send + Sync + 'static,
     B: Backend + Send + 'static,
 {
     pub fn new(
         ring: EngineRing<B, EB>,
-        url_limit: Option<usize>,
+        stop_conditions: StopConditions,
         retry_policy: RetryPolicy,
         retry_pool: RetryPool,
         use_robots: bool,
         robot: String,
+        robot_ctrl: RobotsMap,
     ) -> Self {
+        let (engine_events, engine_event_receiver) = mpsc::unbounded_channel();
         Self {
-            url_limit,
+            stop_conditions,
+            stop_reason: None,
             ring,
             retry_policy,
             retry_pool,
             robot,
             use_robot_check: use_robots,
-            robot_ctrl: RobotsMap::default(),
-            urls_pool: Vec::new(),
-            seen_list: HashSet::new(),
+            robot_ctrl,
+            frontier: Box::new(MemoryFrontier::default()),
             spawned_jobs: HashMap::new(),
+            warc: None,
+            error_policy: ErrorPolicy::default(),
+            failed_domains: HashSet::new(),
+            filters: Vec::new(),
+            observers: Observers::default(),
+            sinks: Vec::new(),
+            live_stats: None,
+            started: Instant::now(),
+            activity: Arc::default(),
+            control: None,
+            is_paused: false,
+            engine_events,
+            engine_event_receiver: Some(engine_event_receiver),
+            count_recycling: 0,
         }
     }
 
+    /// Sets filters which found urls must pass.
+    pub fn set_filters(&mut self, filters: Vec<Filter>) {
+        self.filters = filters;
+    }
+
+    pub fn add_observer(&mut self, observer: Arc<dyn CrawlObserver>) {
+        self.observers.push(observer);
+    }
+
+    /// Sets a queue of urls and a store of seen ones.
+    pub fn set_frontier(&mut self, frontier: Box<dyn Frontier>) {
+        self.frontier = frontier;
+    }
+
+    /// Adds a sink which receives data as soon as it's collected.
+    pub fn add_sink(&mut self, sink: Box<dyn Sink>) {
+        self.sinks.push(sink);
+    }
+
+    /// Sets a channel which statistics are published to while crawling.
+    pub fn set_live_statistics(&mut self, sender: watch::Sender<Statistics>) {
+        self.live_stats = Some(sender);
+    }
+
+    /// Sets a channel of commands which control the crawl while it runs.
+    pub fn set_control(&mut self, commands: mpsc::UnboundedReceiver<Command>) {
+        self.control = Some(commands);
+    }
+
+    pub fn set_error_policy(&mut self, policy: ErrorPolicy) {
+        self.error_policy = policy;
+    }
+
+    pub fn set_warc_writer(&mut self, warc: WarcWriter) {
+        self.warc = Some(warc);
+    }
+
     pub async fn start(mut self, seed: Vec<Url>, notify: Arc<Notify>) -> (Vec<Value>, Statistics) {
-        if seed.is_empty() {
+        // a frontier might keep urls of a previous crawl
+        if seed.is_empty() && self.frontier.is_empty() {
             return (Vec::new(), Statistics::default());
         }
 
-        self.keep_urls(seed);
+        self.started = Instant::now();
+        self.enqueue_seeds(seed).await;
         let (s_result, r_result) = unbounded();
         let (s_urls, r_urls) = unbounded();
-        if let Err(err) = self.spawn_engines(r_urls.clone(), s_result.clone()).await {
-            error!("Error occured while spawning engines {}", err);
-            return (Vec::new(), Statistics::default());
-        };
-
-        let mut job_counter = 0usize;
-        while let Some(url) = self.get_url() {
-            // don't push in channel more urls then engines
-            // it's important because of our stop strategies.
-            if job_counter == self.ring.capacity() {
-                break;
-            }
-
-            if self.use_robot_check {
-                if let Ok(true) = self.robot_ctrl.is_allowed(&self.robot, url.clone()).await {
-                    s_urls.send(url).await.unwrap();
-                    job_counter += 1;
-                }
-                // ignore errors and not allowed urls
-            } else {
-                s_urls.send(url).await.unwrap();
-                job_counter += 1;
+        match self.ring.start().await {
+            Ok(count) => info!("Started {} engines", count),
+            Err(err) => {
+                error!("None of engines started {}", err);
+                let mut stats = Statistics {
+                    count_failed_engines: self.ring.count_failed_builds(),
+                    ..Statistics::default()
+                };
+                self.publish_statistics(&mut stats, 0);
+                return (Vec::new(), stats);
             }
         }
 
-        println!("job_counter {}", job_counter);
+        self.spawn_engines(&r_urls, &s_result);
 
         let mut stats = Statistics::default();
+        let mut job_counter = 0usize;
+        self.send_urls(&s_urls, &mut job_counter, &mut stats).await;
+
         let mut results = Vec::new();
-        let mut is_closed = false;
+        let mut control = self.control.take();
+        let mut engine_events = self.engine_event_receiver.take().unwrap();
+        let mut publish_interval = tokio::time::interval(PUBLISH_INTERVAL);
         loop {
             tokio::select! {
-                Ok(EngineResult { engine, result }) = r_result.recv() => {
-                    stats.count_visited += 1;
-
+                Ok(EngineResult { engine, url, result, is_recycled, proxy, is_blocked }) = r_result.recv() => {
                     job_counter -= 1;
+                    // engines which were working when a limit was reached finish over it
+                    let is_over_limit = self.stop_conditions.is_limit_reached(&stats).is_some();
 
-                    match result {
-                        Ok((urls, data)) => {
-                            results.push(data);
-                            if self.inc_limit() {
-                                println!("LIMIIIIY {}", job_counter);
-                                is_closed = true;
-                            }
-
-                            self.keep_urls(urls);
-
-                            stats.count_collected += 1;
-                        }
-                        Err(err) if err.is_timeout() && self.retry_policy != RetryPolicy::No => {
-                            error!("Engine {} got a timeout error {}; Put url back in the queue", engine, err);
-                            stats.count_retries += 1;
+                    if let Some(proxy) = proxy {
+                        let health = stats.proxies.entry(proxy).or_default();
+                        health.count_visited += 1;
+                        health.count_errors += result.is_err() as usize;
+                        health.count_blocks += is_blocked as usize;
+                        health.count_rotations += is_recycled as usize;
+                    }
 
-                            let url = err.address().unwrap();
-                            if !self.retry_pool.keep_retry(url.clone()) {
-                                self.mark_visited(url.clone())
-                            }
+                    match &result {
+                        Ok(page) => {
+                            stats.record_page(page);
+                            let host = stats.host(&url);
+                            host.count_visited += 1;
+                            host.count_collected += (!is_over_limit && page.is_collected()) as usize;
+                            self.observers.on_page_result(page).await;
                         }
                         Err(err) => {
-                            stats.count_errors += 1;
-                            error!("Engine {} got a error {}", engine, err);
+                            stats.host(&url).count_errors += 1;
+                            *stats.errors.entry(err.class()).or_default() += 1;
+                            self.observers.on_error(&url, err).await;
                         }
                     }
 
-                    if !is_closed {
-                        // todo: unify a STOP interface
-                        if let Err(err) = self.spawn_engines(r_urls.clone(), s_result.clone()).await {
-                            error!("Error occured while spawning engine {}", err);
-                            return (Vec::new(), Statistics::default());
-                        };
-
-                        while let Some(url) = self.get_url() {
-                            // don't push in channel more urls then engines
-                            // it's important because of our stop strategies.
-                            if job_counter == self.ring.capacity() {
-                                break;
-                            }
+                    match result {
+                        Err(err) if err.class() == ErrorClass::SessionLost => {
+                            self.discard_engine(engine, url, err, &mut stats).await;
+                        }
+                        Ok(page) => {
+                            stats.count_visited += 1;
 
-                            if self.use_robot_check {
-                                if let Ok(true) = self.robot_ctrl.is_allowed(&self.robot, url.clone()).await {
-                                    s_urls.send(url).await.unwrap();
-                                    job_counter += 1;
+                            if let Some(warc) = self.warc.as_mut() {
+                                if let Err(err) = warc.write_page(&page) {
+                                    error!("Failed to write {} to a WARC file {}", page.url, err);
                                 }
-                                // ignore errors and not allowed urls
+                            }
+
+                            if is_over_limit {
+                                info!(
+                                    "Data of {} is dropped as the crawl reached its limit",
+                                    page.url
+                                );
                             } else {
-                                s_urls.send(url).await.unwrap();
-                                job_counter += 1;
+                                let is_collected = page.is_collected();
+                                let Page { url, urls, data, .. } = page;
+                                // a page is skipped if there's no data or it's `null`
+                                if let Some(data) = data.filter(|_| is_collected) {
+                                    for sink in &mut self.sinks {
+                                        if let Err(err) = sink.write(&data).await {
+                                            error!("Failed to write data of {} to a sink {}", url, err);
+                                        }
+                                    }
+
+                                    results.push(data);
+                                    stats.count_collected += 1;
+                                }
+
+                                self.discover_urls(urls, &url, &mut stats).await;
                             }
                         }
-                    }
+                        Err(err) => {
+                            stats.count_visited += 1;
 
-                    if job_counter == 0  {
-                        s_urls.close();
-                        r_urls.close();
-                        for (_, f) in self.spawned_jobs {
-                            f.await.unwrap();
+                            let action = self.handle_error(engine, url, err, &mut stats).await;
+                            if action == ErrorAction::Abort {
+                                error!("Abort the crawl");
+                                self.stop(StopReason::Aborted, &s_urls, &r_urls, &mut job_counter);
+                            }
                         }
-                        break;
                     }
 
-                    if self.spawned_jobs.is_empty() {
-                        break;
+                    if is_recycled {
+                        self.close_engine(engine, true);
                     }
                 }
+                Some(event) = engine_events.recv() => {
+                    self.handle_engine_event(event, &r_urls, &s_result);
+                }
                 _ = notify.notified() => {
-                    info!("Waiting for working engines");
-                    is_closed = true;
-                    s_urls.close();
+                    self.stop(StopReason::Interrupted, &s_urls, &r_urls, &mut job_counter);
+                }
+                Some(command) = recv_command(&mut control) => {
+                    match command {
+                        Command::Pause => {
+                            info!("Dispatching is paused");
+                            self.is_paused = true;
+                        }
+                        Command::Resume => {
+                            info!("Dispatching is resumed");
+                            self.is_paused = false;
+                        }
+                        Command::AddSeeds(urls) => self.enqueue_seeds(urls).await,
+                        Command::SetLimit(limit) => self.stop_conditions.max_collected = limit,
+                        Command::Stop => {
+                            self.stop(StopReason::Interrupted, &s_urls, &r_urls, &mut job_counter);
+                        }
+                    }
+                }
+                // a time limit is checked even if engines are stuck on pages
+                _ = publish_interval.tick() => (),
+            }
+
+            if self.stop_reason.is_none() {
+                let elapsed = self.started.elapsed();
+                if let Some(reason) = self.stop_conditions.check(&stats, elapsed) {
+                    self.stop(reason, &s_urls, &r_urls, &mut job_counter);
+                }
+            }
+
+            if self.stop_reason.is_none() && !self.is_paused {
+                self.spawn_engines(&r_urls, &s_result);
+                self.send_urls(&s_urls, &mut job_counter, &mut stats).await;
+            }
+
+            self.publish_statistics(&mut stats, job_counter);
+
+            // a paused crawl waits for being resumed even if engines are idle
+            if self.is_paused && self.stop_reason.is_none() {
+                continue;
+            }
+
+            // retries which aren't due yet are dispatched on a next tick
+            if job_counter == 0 && (self.stop_reason.is_some() || self.retry_pool.is_empty()) {
+                s_urls.close();
+                r_urls.close();
+                for (engine, f) in self.spawned_jobs.drain() {
+                    if let Err(err) = f.await {
+                        error!("A task of engine {} failed {}", engine, err);
+                    }
                 }
+                break;
+            }
+
+            if self.spawned_jobs.is_empty()
+                && self.ring.count_building() == 0
+                && self.count_recycling == 0
+            {
+                break;
             }
         }
 
+        self.wait_engines(&mut engine_events).await;
+
+        stats.failures = self.retry_pool.failures();
+        stats.count_failed_engines = self.ring.count_failed_builds();
+        self.observers.on_finish(&stats).await;
+        self.publish_statistics(&mut stats, 0);
+
         (results, stats)
     }
 
-    fn filter_urls(&mut self, urls: Vec<Url>) -> Vec<Url> {
-        let mut r = Vec::new();
-        for url in urls.into_iter() {
-            if self.seen_list.insert(url.clone()) {
-                r.push(url)
+    /// Stops dispatching urls.
+    /// Urls which engines haven't taken yet are dropped so only pages which are being worked on are finished.
+    fn stop(
+        &mut self,
+        reason: StopReason,
+        sender: &Sender<Url>,
+        receiver: &Receiver<Url>,
+        job_counter: &mut usize,
+    ) {
+        if self.stop_reason.is_some() {
+            return;
+        }
+
+        info!(
+            "The crawl is stopped ({}), waiting for working engines",
+            reason.name()
+        );
+        self.stop_reason = Some(reason);
+        sender.close();
+        while receiver.try_recv().is_ok() {
+            *job_counter -= 1;
+        }
+    }
+
+    /// Updates gauges of the current state and sends statistics to watchers.
+    fn publish_statistics(&self, stats: &mut Statistics, job_counter: usize) {
+        stats.elapsed = self.started.elapsed();
+        stats.frontier_size = self.frontier.len();
+        stats.retry_pool_size = self.retry_pool.len();
+        stats.count_engines = self.ring.capacity();
+        stats.count_busy_engines = job_counter.min(stats.count_engines);
+        stats.is_paused = self.is_paused;
+        stats.stop_reason = self.stop_reason;
+        let mut engines = self
+            .activity
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        engines.sort_by_key(|engine| engine.id);
+        stats.engines = engines;
+
+        if let Some(sender) = self.live_stats.as_ref() {
+            // nobody might be watching
+            let _ = sender.send(stats.clone());
+        }
+    }
+
+    /// Waits for a task of an engine which stopped in background,
+    /// so the crawl isn't blocked while its session is closed.
+    /// A recycled engine is released once its session is closed,
+    /// then a new engine is built in its place on the next spawn.
+    fn close_engine(&mut self, engine: EngineId, is_recycled: bool) {
+        let handle = match self.spawned_jobs.remove(&engine) {
+            Some(handle) => handle,
+            None => return,
+        };
+
+        self.count_recycling += is_recycled as usize;
+        let events = self.engine_events.clone();
+        tokio::spawn(async move {
+            if let Err(err) = handle.await {
+                error!("A task of engine {} failed {}", engine, err);
+            }
+
+            if is_recycled {
+                // a crawl is waiting for recycled engines so it's still there
+                let _ = events.send(EngineEvent::Recycled(engine));
+            }
+        });
+    }
+
+    fn handle_engine_event(
+        &mut self,
+        event: EngineEvent<B>,
+        recv: &Receiver<Url>,
+        sender: &Sender<EngineResult>,
+    ) {
+        match event {
+            EngineEvent::Built(result) => match self.ring.finish_build(result) {
+                Ok(engine) => self.run_engine(engine, recv, sender),
+                Err(err) => error!(
+                    "Failed to build an engine, {} engines left; {}",
+                    self.ring.capacity(),
+                    err
+                ),
+            },
+            EngineEvent::Recycled(engine) => {
+                info!("Engine {} is recycled", engine);
+                self.count_recycling -= 1;
+                self.ring.release(engine);
+            }
+        }
+    }
+
+    /// Waits for engines which are built or recycled in background when a crawl is over.
+    /// Sessions of engines which were built too late are closed.
+    async fn wait_engines(&mut self, events: &mut mpsc::UnboundedReceiver<EngineEvent<B>>) {
+        while self.ring.count_building() + self.count_recycling > 0 {
+            match events.recv().await {
+                Some(EngineEvent::Built(result)) => {
+                    if let Ok(engine) = self.ring.finish_build(result) {
+                        engine.backend.close().await;
+                    }
+                }
+                Some(EngineEvent::Recycled(..)) => self.count_recycling -= 1,
+                None => break,
             }
         }
+    }
+
+    /// Discards an engine which session is lost.
+    /// A url it worked on is requeued without being considered failed
+    /// unless the engine can't be rebuilt anymore,
+    /// then the error is handled according to the error policy.
+    async fn discard_engine(
+        &mut self,
+        engine: EngineId,
+        url: Url,
+        err: BackendError,
+        stats: &mut Statistics,
+    ) {
+        // an engine's task exits right after its session is lost
+        self.close_engine(engine, false);
 
-        r
+        if self.ring.discard(engine) {
+            info!(
+                "Engine {} lost its session and is going to be rebuilt; {}",
+                engine, err
+            );
+            stats.count_rebuilds += 1;
+            self.frontier.push(url);
+        } else {
+            error!(
+                "Engine {} lost its session and the limit of rebuilds is reached, {} engines left",
+                engine,
+                self.ring.capacity()
+            );
+            stats.count_visited += 1;
+            self.handle_error(engine, url, err, stats).await;
+        }
     }
 
-    fn inc_limit(&mut self) -> bool {
-        match self.url_limit.as_mut() {
-            Some(0) => true,
-            Some(limit) => {
-                *limit -= 1;
-                *limit == 0
+    /// Handles an error according to the error policy.
+    /// It returns a taken action.
+    async fn handle_error(
+        &mut self,
+        engine: EngineId,
+        url: Url,
+        err: BackendError,
+        stats: &mut Statistics,
+    ) -> ErrorAction {
+        let class = err.class();
+        let action = match self.error_policy.action(class) {
+            ErrorAction::Retry | ErrorAction::Backoff if self.retry_policy == RetryPolicy::No => {
+                ErrorAction::Skip
+            }
+            action => action,
+        };
+
+        error!(
+            "Engine {} got a {} error {}; {:?}",
+            engine,
+            class.name(),
+            err,
+            action
+        );
+
+        match action {
+            ErrorAction::Retry | ErrorAction::Backoff => {
+                // a delay requested by a server takes precedence
+                let schedule = match err.retry_after() {
+                    Some(delay) => Schedule::After(delay),
+                    None if action == ErrorAction::Backoff => Schedule::Backoff,
+                    None => Schedule::Fixed,
+                };
+                let reason = format!("{}: {}", class.name(), err);
+                let is_kept = self.retry_pool.schedule(url.clone(), schedule, reason);
+
+                if is_kept {
+                    stats.count_retries += 1;
+                    let history = self.retry_pool.history(&url);
+                    let attempt = history.len();
+                    let delay = history.last().and_then(|a| a.delay).unwrap_or_default();
+                    self.observers
+                        .on_retry_scheduled(&url, attempt, delay)
+                        .await;
+                } else {
+                    stats.count_errors += 1;
+                    self.mark_visited(url)
+                }
+            }
+            ErrorAction::FailDomain => {
+                stats.count_errors += 1;
+                if let Some(domain) = url.domain() {
+                    info!("Domain {} is considered to be failing", domain);
+                    self.failed_domains.insert(domain.to_owned());
+                }
+            }
+            ErrorAction::Skip | ErrorAction::Abort => {
+                stats.count_errors += 1;
             }
-            None => false,
         }
+
+        action
+    }
+
+    fn is_domain_failed(&self, url: &Url) -> bool {
+        url.domain()
+            .map_or(false, |domain| self.failed_domains.contains(domain))
     }
 
     fn mark_visited(&mut self, url: Url) {
-        self.seen_list.insert(url);
+        self.frontier.mark_seen(&url);
+    }
+
+    async fn get_url(&mut self) -> Option<Url> {
+        loop {
+            let url = self.next_url()?;
+            if !self.is_domain_failed(&url) {
+                return Some(url);
+            }
+
+            self.observers
+                .on_url_filtered(&url, FilterReason::FailedDomain)
+                .await;
+        }
     }
 
-    fn get_url(&mut self) -> Option<Url> {
+    fn next_url(&mut self) -> Option<Url> {
         match self.retry_policy {
-            RetryPolicy::No => self.urls_pool.pop(),
-            RetryPolicy::RetryFirst => self
-                .retry_pool
-                .get_url(self.urls_pool.is_empty())
-                .or_else(|| self.urls_pool.pop()),
-            RetryPolicy::RetryLast => self
-                .urls_pool
-                .pop()
-                .or_else(|| self.retry_pool.get_url(self.urls_pool.is_empty())),
+            RetryPolicy::No => self.frontier.pop(),
+            RetryPolicy::RetryFirst => self.retry_pool.get_url().or_else(|| self.frontier.pop()),
+            RetryPolicy::RetryLast => self.frontier.pop().or_else(|| self.retry_pool.get_url()),
         }
     }
 
     fn is_any_urls(&mut self) -> bool {
-        !(self.retry_pool.is_empty() && self.urls_pool.is_empty())
+        !(self.retry_pool.is_empty() && self.frontier.is_empty())
+    }
+
+    /// Keeps seeds which are new.
+    async fn enqueue_seeds(&mut self, urls: Vec<Url>) {
+        for url in self.keep_urls(urls) {
+            self.observers.on_url_enqueued(&url, None).await;
+        }
+    }
+
+    /// Queues urls which pass filters and weren't seen before.
+    /// It returns the queued urls.
+    fn keep_urls(&mut self, mut urls: Vec<Url>) -> Vec<Url> {
+        urls.retain(|url| !self.filters.iter().any(|f| f.is_ignored(url)));
+        let frontier = &mut self.frontier;
+        urls.retain(|url| frontier.mark_seen(url));
+        for url in &urls {
+            self.frontier.push(url.clone());
+        }
+
+        urls
     }
 
-    fn keep_urls(&mut self, urls: Vec<Url>) {
-        let urls = self.filter_urls(urls);
-        self.urls_pool.extend(urls);
+    /// Keeps urls found on a page letting observers decide on their order.
+    async fn discover_urls(&mut self, urls: Vec<Url>, page: &Url, stats: &mut Statistics) {
+        let mut kept = Vec::new();
+        for url in urls {
+            match self.filters.iter().find(|f| f.is_ignored(&url)) {
+                Some(filter) => {
+                    stats.count_filtered += 1;
+                    self.observers
+                        .on_url_filtered(&url, FilterReason::Filter(filter))
+                        .await;
+                }
+                None => kept.push(url),
+            }
+        }
+        let urls = kept;
+
+        if self.observers.is_empty() {
+            self.keep_urls(urls);
+            return;
+        }
+
+        let mut kept = Vec::new();
+        let mut prioritized = Vec::new();
+        for url in urls {
+            if !self.frontier.mark_seen(&url) {
+                continue;
+            }
+
+            let discovery = self.observers.on_url_discovered(&url, page).await;
+            if discovery != Discovery::Skip {
+                self.observers.on_url_enqueued(&url, Some(page)).await;
+            }
+
+            match discovery {
+                Discovery::Keep => kept.push(url),
+                Discovery::Skip => (),
+                Discovery::Prioritize => prioritized.push(url),
+                Discovery::Defer => self.frontier.defer(url),
+            }
+        }
+
+        // the last queued urls are visited first
+        for url in kept.into_iter().chain(prioritized) {
+            self.frontier.push(url);
+        }
     }
 
-    async fn spawn_engines(
+    /// Sends urls to engines while there are free ones.
+    /// Urls which aren't allowed by robots.txt are dropped.
+    async fn send_urls(
         &mut self,
-        recv: Receiver<Url>,
-        sender: Sender<EngineResult>,
-    ) -> io::Result<()> {
-        while self.is_there_free_engine() && self.is_any_urls() {
-            let engine = self.ring.obtain().await?;
-            let id = engine.id;
+        sender: &Sender<Url>,
+        job_counter: &mut usize,
+        stats: &mut Statistics,
+    ) {
+        // don't push in channel more urls then engines
+        // it's important because of our stop strategies.
+        while *job_counter < self.ring.capacity() {
+            let url = match self.get_url().await {
+                Some(url) => url,
+                None => break,
+            };
 
-            info!("Spawn engine {}", id);
+            if self.use_robot_check {
+                // errors are ignored as well
+                let is_allowed = matches!(
+                    self.robot_ctrl.is_allowed(&self.robot, url.clone()).await,
+                    Ok(true)
+                );
+                self.observers.on_robots_checked(&url, is_allowed).await;
 
-            let handler = spawn_engine(engine, recv.clone(), sender.clone());
+                if is_allowed {
+                    sender.send(url).await.unwrap();
+                    *job_counter += 1;
+                } else {
+                    stats.count_robots_denied += 1;
+                    self.observers
+                        .on_url_filtered(&url, FilterReason::Robots)
+                        .await;
+                }
+            } else {
+                sender.send(url).await.unwrap();
+                *job_counter += 1;
+            }
+        }
+    }
 
-            // it's OK that it possibly rewrites an old handler which will drop it
-            self.spawned_jobs.insert(id, handler);
+    /// Spawns engines while there are free slots and urls to work on.
+    /// New engines are built in background and spawned once they're ready,
+    /// an engine which fails to be built is dropped from the ring.
+    fn spawn_engines(&mut self, recv: &Receiver<Url>, sender: &Sender<EngineResult>) {
+        // urls might be left in the channel by an engine which was recycled or discarded
+        while self.is_there_free_engine() && (self.is_any_urls() || !recv.is_empty()) {
+            match self.ring.take_free() {
+                Some(engine) => self.run_engine(engine, recv, sender),
+                None => {
+                    let build = self.ring.build_detached();
+                    let events = self.engine_events.clone();
+                    tokio::spawn(async move {
+                        let _ = events.send(EngineEvent::Built(build.await));
+                    });
+                }
+            }
         }
+    }
+
+    fn run_engine(
+        &mut self,
+        mut engine: Engine<B>,
+        recv: &Receiver<Url>,
+        sender: &Sender<EngineResult>,
+    ) {
+        let id = engine.id;
+        engine.observers = self.observers.clone();
+
+        info!("Spawn engine {}", id);
 
-        Ok(())
+        let handler = spawn_engine(engine, recv.clone(), sender.clone(), self.activity.clone());
+
+        // it's OK that it possibly rewrites an old handler which will drop it
+        self.spawned_jobs.insert(id, handler);
     }
 
     fn is_there_free_engine(&self) -> bool {
-        self.ring.capacity() > self.spawned_jobs.len()
+        self.ring.capacity()
+            > self.spawned_jobs.len() + self.ring.count_building() + self.count_recycling
+    }
+}
+
+/// Receives a next command, it never resolves if there's no control channel.
+async fn recv_command(control: &mut Option<mpsc::UnboundedReceiver<Command>>) -> Option<Command> {
+    match control {
+        Some(control) => control.recv().await,
+        None => futures::future::pending().await,
     }
 }
 
 struct EngineResult {
     engine: usize,
-    result: Result<(Vec<Url>, Value), BackendError>,
+    url: Url,
+    result: Result<Page, BackendError>,
+    /// The engine stopped after the result as it reached its recycle limits.
+    is_recycled: bool,
+    proxy: Option<String>,
+    is_blocked: bool,
 }
 
 fn spawn_engine<B>(
     mut engine: Engine<B>,
     receiver: Receiver<Url>,
     sender: Sender<EngineResult>,
+    activity: Arc<Mutex<HashMap<EngineId, EngineActivity>>>,
 ) -> JoinHandle<()>
 where
     B: Backend + Send + 'static,
@@ -289,17 +1117,89 @@ where
     tokio::spawn(async move {
         while let Ok(url) = receiver.recv().await {
             info!("Engine {} is works on {}", engine.id, url);
-            let result = engine.run(url).await;
+            engine.observers.on_url_dispatched(&url, engine.id).await;
+            activity.lock().unwrap().insert(
+                engine.id,
+                EngineActivity {
+                    id: engine.id,
+                    url: url.clone(),
+                    started: Instant::now(),
+                },
+            );
+            let result = engine.run(url.clone()).await;
+            activity.lock().unwrap().remove(&engine.id);
             info!("Engine {} finished", engine.id);
+
+            // there's no use of an engine which session is lost
+            let is_session_lost =
+                matches!(&result, Err(err) if err.class() == ErrorClass::SessionLost);
+            let is_recycled = !is_session_lost && engine.is_worn_out();
+
             sender
                 .send(EngineResult {
                     engine: engine.id,
+                    url,
                     result,
+                    is_recycled,
+                    proxy: engine.proxy.clone(),
+                    is_blocked: engine.is_blocked(),
                 })
                 .await
                 .unwrap();
+
+            if is_session_lost || is_recycled {
+                break;
+            }
         }
 
         engine.backend.close().await; // important: to manually close a backend
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_percentiles() {
+        let mut histogram = Histogram::default();
+        assert_eq!(histogram.percentile(0.5), None);
+
+        for _ in 0..98 {
+            histogram.record(Duration::from_millis(1));
+        }
+        histogram.record(Duration::from_millis(100));
+        histogram.record(Duration::from_millis(1000));
+
+        assert_eq!(histogram.count(), 100);
+        assert_eq!(histogram.percentile(0.5), Some(Duration::from_millis(1)));
+        assert_eq!(histogram.percentile(0.99), Some(Duration::from_millis(100)));
+        assert_eq!(histogram.percentile(1.0), Some(Duration::from_millis(1000)));
+        assert_eq!(histogram.max(), Duration::from_millis(1000));
+
+        let mut histogram = Histogram::default();
+        histogram.record(Duration::from_millis(150));
+        histogram.record(Duration::from_millis(1000));
+        assert_eq!(histogram.percentile(0.5), Some(Duration::from_millis(152)));
+    }
+
+    #[test]
+    fn statistics_json() {
+        let mut stats = Statistics {
+            count_visited: 2,
+            ..Statistics::default()
+        };
+        stats
+            .host(&Url::parse("http://example.net/page").unwrap())
+            .count_visited += 2;
+        stats.errors.insert(ErrorClass::Timeout, 1);
+        stats.load_time.record(Duration::from_millis(3));
+
+        let json = stats.to_json();
+        assert_eq!(json["visited"], json!(2));
+        assert_eq!(json["hosts"]["example.net"]["visited"], json!(2));
+        assert_eq!(json["errors_by_class"]["timeout"], json!(1));
+        assert_eq!(json["load_time"]["p50_ms"], json!(3));
+        assert_eq!(json["script_time"]["p50_ms"], json!(null));
+    }
+}